@@ -0,0 +1,74 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+
+/// Check if the address can be dialed from the internet, that is, it's not a loopback, private
+/// or otherwise local-only address, nor a relayed circuit address.
+pub fn is_public_addr(addr: &Multiaddr) -> bool {
+    if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+        return false;
+    }
+
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => is_public_ipv4(&ip),
+        Some(Protocol::Ip6(ip)) => is_public_ipv6(&ip),
+        Some(Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_)) => {
+            true
+        }
+        _ => false,
+    }
+}
+
+fn is_public_ipv4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation())
+}
+
+fn is_public_ipv6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // Unique local (fc00::/7) and link local (fe80::/10) ranges.
+    let is_unique_local = first & 0xfe00 == 0xfc00;
+    let is_link_local = first & 0xffc0 == 0xfe80;
+    !(ip.is_unspecified() || ip.is_loopback() || is_unique_local || is_link_local)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn is_public(addr: &str) -> bool {
+        is_public_addr(&Multiaddr::from_str(addr).unwrap())
+    }
+
+    #[test]
+    fn public_addrs() {
+        assert!(is_public("/ip4/1.1.1.1/tcp/8042"));
+        assert!(is_public("/ip6/2001:4860::8888/tcp/8042"));
+        assert!(is_public("/dns4/relay.example.com/tcp/8042"));
+    }
+
+    #[test]
+    fn local_addrs() {
+        assert!(!is_public("/ip4/127.0.0.1/tcp/8042"));
+        assert!(!is_public("/ip4/192.168.1.2/tcp/8042"));
+        assert!(!is_public("/ip4/10.0.0.1/tcp/8042"));
+        assert!(!is_public("/ip4/169.254.0.1/tcp/8042"));
+        assert!(!is_public("/ip4/0.0.0.0/tcp/8042"));
+        assert!(!is_public("/ip6/::1/tcp/8042"));
+        assert!(!is_public("/ip6/fd00::1/tcp/8042"));
+        assert!(!is_public("/ip6/fe80::1/tcp/8042"));
+    }
+
+    #[test]
+    fn circuit_addrs_are_not_public() {
+        assert!(!is_public(
+            "/ip4/1.1.1.1/tcp/8042/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN/p2p-circuit"
+        ));
+    }
+}
@@ -1,8 +1,13 @@
+use std::fs;
 use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
 
 use libp2p::identity::{self, PublicKey};
 use rand_core::{OsRng, RngCore};
 
+use crate::{BlueError, BlueResult};
+
 #[derive(Clone)]
 pub struct Identity {
     key: identity::Keypair,
@@ -23,6 +28,29 @@ impl Identity {
         }
     }
 
+    /// Read the ed25519 secret key from file, if it doesn't exist, generate a new one and save it,
+    /// so that the peer id stays the same between restarts.
+    pub fn load_or_generate(path: &Path) -> BlueResult<Self> {
+        let mut secret = match fs::read(path) {
+            Ok(secret) => secret,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut secret = vec![0u8; 32];
+                OsRng.fill_bytes(&mut secret);
+                fs::write(path, &secret)?;
+                secret
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let secret_key =
+            identity::ed25519::SecretKey::from_bytes(&mut secret).map_err(BlueError::local_err)?;
+        let key = identity::Keypair::Ed25519(secret_key.into());
+        Ok(Self {
+            public: key.public(),
+            key,
+        })
+    }
+
     pub fn get_key(&self) -> identity::Keypair {
         self.key.clone()
     }
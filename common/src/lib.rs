@@ -1,9 +1,13 @@
+mod addr;
 mod error;
 mod identity;
 mod peer;
+mod relay;
 
+pub use addr::*;
 pub use error::*;
 pub use identity::*;
 pub use peer::*;
+pub use relay::*;
 
 pub type BlueResult<T> = std::result::Result<T, BlueError>;
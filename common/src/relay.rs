@@ -0,0 +1,9 @@
+use libp2p::gossipsub::IdentTopic;
+
+/// Gossip topic the relays announce their shutdown on, the relay is the signed source of the
+/// message, so that the peers can move their reservations to other relays.
+pub const RELAY_SHUTDOWN_TOPIC: &str = "/beyond-blue/relay-shutdown/1.0.0";
+
+pub fn relay_shutdown_topic() -> IdentTopic {
+    IdentTopic::new(RELAY_SHUTDOWN_TOPIC)
+}
@@ -1,14 +1,16 @@
 use std::sync::{Arc, Mutex};
 
+use bevy::app::AppExit;
 use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::*;
 use bevy_rapier2d::prelude::*;
-use beyond_blue::{GameMessage, GameState, HeroAction, HeroPlugin, NpcPlugin, PIXELS_PER_METER};
+use beyond_blue::{
+    move_validator, GameMessage, GameState, HeroAction, HeroPlugin, NpcPlugin, PIXELS_PER_METER,
+};
 use clap::Parser;
-use common::*;
 use leafwing_input_manager::prelude::*;
+use peer::{OverflowPolicy, PeerHandle};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc;
 
 const WINDOW_WIDTH: usize = 600;
 const WINDOW_HEIGHT: usize = 480;
@@ -16,9 +18,9 @@ const WINDOW_HEIGHT: usize = 480;
 #[derive(Debug, Parser)]
 #[clap(name = "Example Beyond Blue peer")]
 struct Opts {
-    /// The listening address
-    #[clap(long)]
-    relay_address: url::Url,
+    /// Address of the relay http api, repeat to reserve on several relays
+    #[clap(long, required = true)]
+    relay_address: Vec<url::Url>,
 }
 
 #[tokio::main]
@@ -48,6 +50,7 @@ async fn main() {
         .add_plugin(InputManagerPlugin::<HeroAction>::default())
         .add_startup_system(setup_physics.label("main_setup"))
         .add_startup_system(setup_network.label("net_setup"))
+        .add_system(shutdown_network)
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(
             PIXELS_PER_METER,
         ))
@@ -64,25 +67,28 @@ fn setup_physics(mut commands: Commands, mut rapier_config: ResMut<RapierConfigu
 }
 
 fn setup_network(mut commands: Commands, runtime: Res<Runtime>, opts: Res<Opts>) {
-    let (local_in, local_out) = mpsc::channel(32);
-    let (remote_in, remote_out) = mpsc::channel(32);
-
+    // Moves carry the latest position, older ones can be skipped if the game lags behind.
+    let config = peer::SwarmConfig::default().with_overflow_policy(OverflowPolicy::CoalesceLatest);
     let relay_address = opts.relay_address.clone();
-    runtime.spawn(async move {
-        let id = common::Identity::from_file("nothing".into());
-
-        tokio::spawn(async move {
-            let res = peer::Swarm::new_with_default_transport(id.get_key())
-                .await?
-                .spawn::<GameMessage>(relay_address, remote_in, local_out)
-                .await;
+    let id = common::Identity::from_file("nothing".into());
+    let swarm = futures::executor::block_on(peer::Swarm::new_with_config(id.get_key(), config))
+        .expect("Failed to create peer swarm");
 
-            log::info!("Game swarm result: {:?}", res);
+    // Swarm task is spawned on the game runtime.
+    let _guard = runtime.enter();
+    let (handle, events) = swarm
+        .start_with_validator::<GameMessage, _>(relay_address, move_validator())
+        .expect("Failed to start peer swarm");
 
-            BlueResult::Ok(())
-        });
-    });
+    // Connection paths are read by the game to know which peers are relayed.
+    commands.insert_resource(handle.connection_table());
+    commands.insert_resource(handle);
+    commands.insert_resource(Arc::new(Mutex::new(events)));
+}
 
-    commands.insert_resource(local_in);
-    commands.insert_resource(Arc::new(Mutex::new(remote_out)));
+fn shutdown_network(mut exit: EventReader<AppExit>, handle: Res<PeerHandle<GameMessage>>) {
+    if exit.iter().next().is_some() {
+        // Block the exit until the peers are told goodbye.
+        _ = futures::executor::block_on(handle.shutdown());
+    }
 }
@@ -4,7 +4,7 @@ use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::{FillMode as LyonFillMode, *};
 use bevy_rapier2d::prelude::*;
 use leafwing_input_manager::prelude::*;
-use tokio::sync::mpsc;
+use peer::PeerHandle;
 
 use crate::{GameMessage, GameState};
 
@@ -92,7 +92,7 @@ pub fn hero_dampening_system(
 }
 
 fn hero_force(
-    to_server: ResMut<mpsc::Sender<GameMessage>>,
+    to_server: Res<PeerHandle<GameMessage>>,
     game_state: ResMut<GameState>,
     action_state_query: Query<&ActionState<HeroAction>>,
     mut query: Query<(&mut ExternalImpulse, &mut Velocity, &Transform, &mut Hero)>,
@@ -124,7 +124,7 @@ fn hero_force(
         if let Ok((_, _, transform, mut hero)) = query.get_mut(hero) {
             hero.update_timer.tick(time.delta());
             if hero.update_timer.finished() {
-                _ = to_server.try_send(GameMessage::Move(
+                _ = to_server.try_publish(GameMessage::Move(
                     transform.translation.x,
                     transform.translation.y,
                     transform.rotation,
@@ -1,6 +1,7 @@
 mod hero;
 mod npc;
 mod state;
+mod validation;
 
 use bevy::prelude::Quat;
 use peer::NetworkEvent;
@@ -9,6 +10,7 @@ use serde::{Deserialize, Serialize};
 pub use hero::*;
 pub use npc::*;
 pub use state::*;
+pub use validation::*;
 
 pub const PIXELS_PER_METER: f32 = 492.3;
 
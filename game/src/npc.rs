@@ -1,11 +1,12 @@
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
 
 use bevy::prelude::*;
 use bevy_prototype_lyon::prelude::{FillMode as LyonFillMode, *};
 use bevy_rapier2d::prelude::*;
+use libp2p::PeerId;
+use peer::EventStream;
 
-use crate::{state::GameState, GameEvent};
+use crate::{state::GameState, GameMessage};
 
 pub struct NpcPlugin;
 
@@ -19,15 +20,15 @@ impl Plugin for NpcPlugin {
 pub struct Npc;
 
 pub fn handle_conn_events(
-    commands: Commands,
-    game_state: ResMut<GameState>,
-    from_server: Res<Arc<Mutex<mpsc::Receiver<GameEvent>>>>,
+    mut commands: Commands,
+    mut game_state: ResMut<GameState>,
+    from_server: Res<Arc<Mutex<EventStream<GameMessage>>>>,
     mut query: Query<&mut Transform, With<Npc>>,
 ) {
     // The operation can't be blocking inside the bevy system.
-    if let Ok(msg) = from_server.lock().unwrap().try_recv() {
+    if let Some(msg) = from_server.lock().unwrap().try_next() {
         match msg {
-            peer::NetworkEvent::NewConnection(peer_id) => {
+            peer::NetworkEvent::NewConnection(peer_id, _) => {
                 if game_state.npcs.get(&peer_id).is_none() {
                     spawn_npc(commands, peer_id, game_state);
                 }
@@ -40,11 +41,39 @@ pub fn handle_conn_events(
                     }
                 }
             }
+            peer::NetworkEvent::ConnectionUpgraded { peer, direct } => {
+                log::info!("connection to {} upgraded, direct: {}", peer, direct);
+            }
+            peer::NetworkEvent::HolePunchFailed { peer, error } => {
+                log::info!("hole punch to {} failed: {}", peer, error);
+            }
+            peer::NetworkEvent::Stats { peer, stats } => {
+                log::debug!(
+                    "{} rtt: {:?}, jitter: {:?}, loss: {:.2}",
+                    peer,
+                    stats.rtt,
+                    stats.jitter,
+                    stats.loss
+                );
+            }
+            // Direct messages are not used by the game yet.
+            peer::NetworkEvent::Request { .. } => {}
+            peer::NetworkEvent::IncompatiblePeer { peer, version } => {
+                log::info!("{} runs incompatible version {}", peer, version);
+                if let Some(entity) = game_state.npcs.remove(&peer) {
+                    commands.entity(entity).despawn();
+                }
+            }
+            peer::NetworkEvent::Goodbye(peer_id) => {
+                if let Some(entity) = game_state.npcs.remove(&peer_id) {
+                    commands.entity(entity).despawn();
+                }
+            }
         }
     }
 }
 
-pub fn spawn_npc(mut commands: Commands, peer_id: String, mut game_state: ResMut<GameState>) {
+pub fn spawn_npc(mut commands: Commands, peer_id: PeerId, mut game_state: ResMut<GameState>) {
     let shape = shapes::RegularPolygon {
         sides: 3,
         feature: shapes::RegularPolygonFeature::Radius(10.0),
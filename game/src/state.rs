@@ -1,7 +1,8 @@
 use bevy::{prelude::*, utils::HashMap};
+use libp2p::PeerId;
 
 #[derive(Default)]
 pub struct GameState {
     pub hero: Option<Entity>,
-    pub npcs: HashMap<String, Entity>,
+    pub npcs: HashMap<PeerId, Entity>,
 }
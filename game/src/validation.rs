@@ -0,0 +1,29 @@
+use bevy::{prelude::*, utils::HashMap};
+use libp2p::PeerId;
+use peer::MessageAcceptance;
+
+use crate::GameMessage;
+
+/// Maximum distance in pixels the peer can move between two position updates.
+pub const MAX_MOVE_DISTANCE: f32 = 300.0;
+
+/// Reject moves that teleport peer further than `MAX_MOVE_DISTANCE` from its last position.
+/// Only accepted moves update the position, so the peer can't teleport in two steps.
+pub fn move_validator() -> impl FnMut(&PeerId, &GameMessage) -> MessageAcceptance + Send {
+    let mut positions: HashMap<PeerId, Vec2> = HashMap::default();
+
+    move |peer_id, msg| match msg {
+        GameMessage::Move(x, y, _) => {
+            let position = Vec2::new(*x, *y);
+            match positions.get(peer_id) {
+                Some(last) if last.distance(position) > MAX_MOVE_DISTANCE => {
+                    MessageAcceptance::Reject
+                }
+                _ => {
+                    positions.insert(*peer_id, position);
+                    MessageAcceptance::Accept
+                }
+            }
+        }
+    }
+}
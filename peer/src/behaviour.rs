@@ -1,17 +1,29 @@
-use common::BlueResult;
+use common::{relay_shutdown_topic, BlueError, BlueResult};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::time::Duration;
+use std::iter;
+use std::num::NonZeroU32;
 
 use libp2p::gossipsub::{
-    Gossipsub, GossipsubMessage, MessageAuthenticity, MessageId, ValidationMode,
+    Gossipsub, GossipsubMessage, MessageAuthenticity, MessageId, PeerScoreParams,
+    PeerScoreThresholds, ValidationMode,
 };
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
 use libp2p::ping::{Ping, PingConfig, PingEvent};
 use libp2p::relay::v2::client::{self, Client};
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+};
 use libp2p::{dcutr, gossipsub};
 use libp2p::{identity, NetworkBehaviour};
 
+use crate::{DirectCodec, DirectProtocol, GossipMessageId, SwarmConfig};
+
+const MAX_PING_FAILURES: NonZeroU32 = match NonZeroU32::new(5) {
+    Some(n) => n,
+    None => unreachable!(),
+};
+
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
 pub struct Behaviour {
@@ -20,47 +32,112 @@ pub struct Behaviour {
     pub dcutr: dcutr::behaviour::Behaviour,
     pub gossip: gossipsub::Gossipsub,
     pub ping: Ping,
+    pub direct: RequestResponse<DirectCodec>,
 }
 
 impl Behaviour {
-    pub fn new(client: Client, key: &identity::Keypair) -> BlueResult<Self> {
-        let gossip = Self::new_gossip_config(key)?;
+    pub fn new(client: Client, key: &identity::Keypair, config: &SwarmConfig) -> BlueResult<Self> {
+        let gossip = Self::new_gossip_config(key, config)?;
+
+        let mut direct_config = RequestResponseConfig::default();
+        direct_config.set_request_timeout(config.request_timeout);
 
         Ok(Self {
             relay_client: client,
-            identify: Identify::new(IdentifyConfig::new("/TODO/0.0.1".to_string(), key.public())),
+            identify: Identify::new(IdentifyConfig::new(
+                config.protocol_version.to_string(),
+                key.public(),
+            )),
             dcutr: dcutr::behaviour::Behaviour::new(),
             gossip,
-            ping: Ping::new(PingConfig::new().with_keep_alive(true)),
+            ping: Ping::new(
+                PingConfig::new()
+                    .with_keep_alive(config.ping_keep_alive)
+                    .with_interval(config.ping_interval)
+                    // Lost pings are reported as the packet loss, close only unresponsive peers.
+                    .with_max_failures(MAX_PING_FAILURES),
+            ),
+            direct: RequestResponse::new(
+                DirectCodec,
+                iter::once((DirectProtocol, ProtocolSupport::Full)),
+                direct_config,
+            ),
         })
     }
 
-    fn new_gossip_config(key: &identity::Keypair) -> BlueResult<Gossipsub> {
-        // To content-address message, we can take the hash of message and use it as an ID.
-        let message_id_fn = |message: &GossipsubMessage| {
-            let mut s = DefaultHasher::new();
-            let id = format!("{:?}{:?}", message.source, message.data);
-            id.hash(&mut s);
-            MessageId::from(s.finish().to_string())
+    fn new_gossip_config(key: &identity::Keypair, config: &SwarmConfig) -> BlueResult<Gossipsub> {
+        let gossip = &config.gossip;
+        let message_id_fn = match gossip.message_id {
+            GossipMessageId::SequenceNumber => sequence_message_id,
+            GossipMessageId::ContentHash => content_message_id,
         };
 
         // Set a custom gossipsub
         let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
-            .heartbeat_interval(Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
+            .heartbeat_interval(gossip.heartbeat)
             .validation_mode(ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
-            .message_id_fn(message_id_fn) // content-address messages. No two messages of the
-            // same content will be propagated.
+            .message_id_fn(message_id_fn)
+            .mesh_n(gossip.mesh_n)
+            .mesh_n_low(gossip.mesh_n_low)
+            .mesh_n_high(gossip.mesh_n_high)
+            .mesh_outbound_min(gossip.mesh_outbound_min)
+            .max_transmit_size(gossip.max_transmit_size)
+            .validate_messages() // Messages are forwarded only after the application validates them.
             .build()
-            .expect("Valid config");
+            .map_err(BlueError::local_err)?;
         // build a gossipsub network behaviour
-        let gossipsub: gossipsub::Gossipsub =
+        let mut gossipsub: gossipsub::Gossipsub =
             gossipsub::Gossipsub::new(MessageAuthenticity::Signed(key.clone()), gossipsub_config)
-                .expect("Correct configuration");
+                .map_err(BlueError::local_err)?;
+
+        if gossip.peer_scoring {
+            gossipsub
+                .with_peer_score(peer_score_params(), PeerScoreThresholds::default())
+                .map_err(BlueError::local_err)?;
+        }
+
+        // Relays announce their shutdown, so that the peer can move its reservation in time.
+        gossipsub
+            .subscribe(&relay_shutdown_topic())
+            .map_err(BlueError::local_err)?;
 
         Ok(gossipsub)
     }
 }
 
+/// Score is driven by the messages the game rejects, every rejection costs
+/// `app_specific_weight` points and the peer is graylisted at -80.
+fn peer_score_params() -> PeerScoreParams {
+    PeerScoreParams {
+        app_specific_weight: 10.0,
+        // Gossipsub takes the IP of the connection, which is the relay IP for every relayed
+        // peer, so the colocation penalty would graylist peers only for sharing a relay.
+        ip_colocation_factor_weight: 0.0,
+        ip_colocation_factor_threshold: 10.0,
+        behaviour_penalty_weight: -10.0,
+        behaviour_penalty_threshold: 0.0,
+        ..PeerScoreParams::default()
+    }
+}
+
+/// Every signed message has a unique sequence number, so identical messages sent one after
+/// another, e.g. position updates while standing still, are not deduplicated.
+fn sequence_message_id(message: &GossipsubMessage) -> MessageId {
+    match (message.source, message.sequence_number) {
+        (Some(source), Some(seq)) => MessageId::from(format!("{}{}", source, seq)),
+        _ => content_message_id(message),
+    }
+}
+
+/// To content-address message, we can take the hash of message and use it as an ID.
+/// No two messages of the same content will be propagated.
+fn content_message_id(message: &GossipsubMessage) -> MessageId {
+    let mut s = DefaultHasher::new();
+    let id = format!("{:?}{:?}", message.source, message.data);
+    id.hash(&mut s);
+    MessageId::from(s.finish().to_string())
+}
+
 #[derive(Debug)]
 pub enum Event {
     Ping(PingEvent),
@@ -68,6 +145,7 @@ pub enum Event {
     Relay(client::Event),
     Dcutr(dcutr::behaviour::Event),
     Gossipsub(gossipsub::GossipsubEvent),
+    Direct(RequestResponseEvent<Vec<u8>, Vec<u8>>),
 }
 
 impl From<PingEvent> for Event {
@@ -99,3 +177,9 @@ impl From<gossipsub::GossipsubEvent> for Event {
         Event::Gossipsub(e)
     }
 }
+
+impl From<RequestResponseEvent<Vec<u8>, Vec<u8>>> for Event {
+    fn from(e: RequestResponseEvent<Vec<u8>, Vec<u8>>) -> Self {
+        Event::Direct(e)
+    }
+}
@@ -0,0 +1,209 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use libp2p::core::multiaddr::{Multiaddr, Protocol};
+use tokio::sync::mpsc;
+
+use crate::{OverflowPolicy, ProtocolVersion};
+
+/// Networking configuration of the peer swarm.
+#[derive(Clone, Debug)]
+pub struct SwarmConfig {
+    /// Version of the game messages, peers with incompatible versions are refused.
+    pub protocol_version: ProtocolVersion,
+
+    /// Local addresses the swarm listens on.
+    pub listen_addrs: Vec<Multiaddr>,
+
+    /// Also include websocket transport, needed to dial `/ws` addresses.
+    pub websocket: bool,
+
+    /// Gossipsub parameters.
+    pub gossip: GossipConfig,
+
+    /// Keep connections alive with pings even if there is no other traffic.
+    pub ping_keep_alive: bool,
+
+    /// How often the connected peers are pinged to measure the connection quality.
+    pub ping_interval: Duration,
+
+    /// Number of addresses dialed concurrently for a single peer.
+    pub dial_concurrency_factor: u8,
+
+    /// Number of relays the peer is reserved on at once, the rest are spares for fail over.
+    pub max_relays: usize,
+
+    /// Timeout of the connection upgrade, including the dial.
+    pub connection_timeout: Duration,
+
+    /// How long to wait for the response to a direct request.
+    pub request_timeout: Duration,
+
+    /// Capacity of the channels between the game and the swarm.
+    pub channel_size: usize,
+
+    /// What to do with the received messages when the game doesn't keep up.
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        Self {
+            protocol_version: ProtocolVersion::default(),
+            listen_addrs: vec![Multiaddr::empty()
+                .with(Protocol::from(Ipv4Addr::UNSPECIFIED))
+                .with(Protocol::Tcp(0))],
+            websocket: false,
+            gossip: GossipConfig::realtime(),
+            ping_keep_alive: true,
+            ping_interval: Duration::from_secs(2),
+            dial_concurrency_factor: 10,
+            max_relays: 2,
+            connection_timeout: Duration::from_secs(20),
+            request_timeout: Duration::from_secs(10),
+            channel_size: 32,
+            overflow_policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+impl SwarmConfig {
+    pub fn with_protocol_version(mut self, version: ProtocolVersion) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    pub fn with_listen_addrs(mut self, addrs: Vec<Multiaddr>) -> Self {
+        self.listen_addrs = addrs;
+        self
+    }
+
+    /// Additionally listen on all IPv6 interfaces.
+    pub fn with_ipv6(mut self) -> Self {
+        self.listen_addrs.push(
+            Multiaddr::empty()
+                .with(Protocol::from(Ipv6Addr::UNSPECIFIED))
+                .with(Protocol::Tcp(0)),
+        );
+        self
+    }
+
+    pub fn with_websocket(mut self, websocket: bool) -> Self {
+        self.websocket = websocket;
+        self
+    }
+
+    pub fn with_gossip(mut self, gossip: GossipConfig) -> Self {
+        self.gossip = gossip;
+        self
+    }
+
+    pub fn with_gossip_heartbeat(mut self, interval: Duration) -> Self {
+        self.gossip.heartbeat = interval;
+        self
+    }
+
+    pub fn with_ping_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.ping_keep_alive = keep_alive;
+        self
+    }
+
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    pub fn with_dial_concurrency_factor(mut self, factor: u8) -> Self {
+        self.dial_concurrency_factor = factor;
+        self
+    }
+
+    pub fn with_max_relays(mut self, max_relays: usize) -> Self {
+        self.max_relays = max_relays;
+        self
+    }
+
+    pub fn with_connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = timeout;
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn with_channel_size(mut self, size: usize) -> Self {
+        self.channel_size = size;
+        self
+    }
+
+    pub fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Create a channel of configured size for passing messages to or from the swarm.
+    pub fn channel<T>(&self) -> (mpsc::Sender<T>, mpsc::Receiver<T>) {
+        mpsc::channel(self.channel_size)
+    }
+}
+
+/// How gossipsub message IDs are derived.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GossipMessageId {
+    /// Source peer and message sequence number, every published message is delivered.
+    SequenceNumber,
+    /// Source peer and message content, identical messages from the same peer are dropped.
+    ContentHash,
+}
+
+/// Gossipsub parameters, see `libp2p::gossipsub::GossipsubConfig` for their meaning.
+#[derive(Clone, Debug)]
+pub struct GossipConfig {
+    pub heartbeat: Duration,
+    pub message_id: GossipMessageId,
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    pub mesh_outbound_min: usize,
+    pub max_transmit_size: usize,
+    /// Enable gossipsub peer scoring with default parameters.
+    pub peer_scoring: bool,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self::realtime()
+    }
+}
+
+impl GossipConfig {
+    /// Preset for realtime game state, frequent small messages in a small mesh.
+    pub fn realtime() -> Self {
+        Self {
+            heartbeat: Duration::from_millis(200),
+            message_id: GossipMessageId::SequenceNumber,
+            mesh_n: 4,
+            mesh_n_low: 2,
+            mesh_n_high: 8,
+            mesh_outbound_min: 1,
+            max_transmit_size: 16 * 1024,
+            peer_scoring: true,
+        }
+    }
+
+    /// Slow heartbeat that doesn't clutter the log space, useful for debugging.
+    pub fn debug() -> Self {
+        Self {
+            heartbeat: Duration::from_secs(10),
+            message_id: GossipMessageId::ContentHash,
+            mesh_n: 6,
+            mesh_n_low: 5,
+            mesh_n_high: 12,
+            mesh_outbound_min: 2,
+            max_transmit_size: 65536,
+            peer_scoring: true,
+        }
+    }
+}
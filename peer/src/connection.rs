@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use libp2p::core::ConnectedPoint;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::PeerStats;
+
+pub type SharedConnectionTable = Arc<Mutex<ConnectionTable>>;
+
+/// The way a connection to the remote peer is established.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionPath {
+    /// Connected to the remote peer directly.
+    Direct,
+    /// Connected via `/p2p-circuit` on the relay.
+    Relayed,
+}
+
+impl From<&ConnectedPoint> for ConnectionPath {
+    fn from(endpoint: &ConnectedPoint) -> Self {
+        if endpoint.is_relayed() {
+            ConnectionPath::Relayed
+        } else {
+            ConnectionPath::Direct
+        }
+    }
+}
+
+/// Connection paths and quality of the currently connected peers and NAT traversal statistics.
+#[derive(Default, Clone, Debug)]
+pub struct ConnectionTable {
+    paths: HashMap<PeerId, ConnectionPath>,
+    stats: HashMap<PeerId, PeerStats>,
+
+    /// Number of successful direct connection upgrades.
+    pub hole_punch_succeeded: u32,
+
+    /// Number of failed direct connection upgrades.
+    pub hole_punch_failed: u32,
+}
+
+impl ConnectionTable {
+    pub fn get(&self, peer: &PeerId) -> Option<ConnectionPath> {
+        self.paths.get(peer).copied()
+    }
+
+    pub fn get_all(&self) -> Vec<(PeerId, ConnectionPath)> {
+        self.paths.iter().map(|(p, c)| (*p, *c)).collect()
+    }
+
+    /// Latency and loss estimates of the peer, available after the first ping.
+    pub fn stats(&self, peer: &PeerId) -> Option<PeerStats> {
+        self.stats.get(peer).copied()
+    }
+
+    pub fn get_all_stats(&self) -> Vec<(PeerId, PeerStats)> {
+        self.stats.iter().map(|(p, s)| (*p, *s)).collect()
+    }
+
+    pub fn relayed_count(&self) -> usize {
+        self.paths
+            .values()
+            .filter(|p| **p == ConnectionPath::Relayed)
+            .count()
+    }
+
+    /// Set the path for the peer, a direct path is never replaced by a relayed one, because
+    /// peer keeps using the direct connection once it's established.
+    pub(crate) fn insert(&mut self, peer: PeerId, path: ConnectionPath) {
+        let entry = self.paths.entry(peer).or_insert(path);
+        if path == ConnectionPath::Direct {
+            *entry = path;
+        }
+    }
+
+    pub(crate) fn set(&mut self, peer: PeerId, path: ConnectionPath) {
+        self.paths.insert(peer, path);
+    }
+
+    pub(crate) fn stats_mut(&mut self, peer: PeerId) -> &mut PeerStats {
+        self.stats.entry(peer).or_default()
+    }
+
+    pub(crate) fn remove(&mut self, peer: &PeerId) {
+        self.paths.remove(peer);
+        self.stats.remove(peer);
+    }
+}
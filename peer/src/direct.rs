@@ -0,0 +1,75 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use libp2p::request_response::{ProtocolName, RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+
+/// Maximum size of the direct request or response in bytes.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Identifies the received request the game has to respond to with `PeerHandle::respond`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ResponseChannelId(pub(crate) u64);
+
+/// Protocol for messages sent directly to a single peer instead of gossiping them.
+#[derive(Debug, Clone)]
+pub struct DirectProtocol;
+
+impl ProtocolName for DirectProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/beyond-blue/direct/1.0.0"
+    }
+}
+
+/// Passes msgpack encoded messages as length prefixed bytes.
+#[derive(Clone, Default)]
+pub struct DirectCodec;
+
+#[async_trait]
+impl RequestResponseCodec for DirectCodec {
+    type Protocol = DirectProtocol;
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &DirectProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_length_prefixed(io, MAX_MESSAGE_SIZE).await
+    }
+
+    async fn read_response<T>(&mut self, _: &DirectProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_length_prefixed(io, MAX_MESSAGE_SIZE).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+        data: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, data).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+        data: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, data).await?;
+        io.close().await
+    }
+}
@@ -0,0 +1,157 @@
+use common::{BlueError, BlueResult};
+use libp2p::PeerId;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::{
+    event_queue, EventSender, EventStream, OverflowPolicy, ProtocolVersion, ResponseChannelId,
+    SharedConnectionTable,
+};
+
+/// Requests from the `PeerHandle` to the running swarm.
+pub(crate) enum Command<M> {
+    Publish(M),
+    SendTo(PeerId, Vec<u8>, oneshot::Sender<BlueResult<Vec<u8>>>),
+    Respond(ResponseChannelId, Vec<u8>),
+    ConnectedPeers(oneshot::Sender<Vec<PeerId>>),
+    Subscribe(EventSender<M>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Result the swarm stopped with, `None` while it's running.
+pub(crate) type SwarmResult = Option<Result<(), String>>;
+
+/// Handle to the running swarm, it can be cloned and shared between the game systems.
+/// The swarm is stopped when all handles are dropped or `shutdown` is called.
+pub struct PeerHandle<M> {
+    commands: mpsc::Sender<Command<M>>,
+    connections: SharedConnectionTable,
+    stopped: watch::Receiver<SwarmResult>,
+    channel_size: usize,
+    overflow_policy: OverflowPolicy,
+    version: ProtocolVersion,
+}
+
+impl<M> Clone for PeerHandle<M> {
+    fn clone(&self) -> Self {
+        Self {
+            commands: self.commands.clone(),
+            connections: self.connections.clone(),
+            stopped: self.stopped.clone(),
+            channel_size: self.channel_size,
+            overflow_policy: self.overflow_policy,
+            version: self.version,
+        }
+    }
+}
+
+impl<M> PeerHandle<M> {
+    pub(crate) fn new(
+        commands: mpsc::Sender<Command<M>>,
+        connections: SharedConnectionTable,
+        stopped: watch::Receiver<SwarmResult>,
+        channel_size: usize,
+        overflow_policy: OverflowPolicy,
+        version: ProtocolVersion,
+    ) -> Self {
+        Self {
+            commands,
+            connections,
+            stopped,
+            channel_size,
+            overflow_policy,
+            version,
+        }
+    }
+
+    /// Broadcast the message to all connected peers.
+    pub async fn publish(&self, msg: M) -> BlueResult<()> {
+        self.send(Command::Publish(msg)).await
+    }
+
+    /// Non blocking version of `publish`, fails if the swarm is busy.
+    pub fn try_publish(&self, msg: M) -> BlueResult<()> {
+        self.commands
+            .try_send(Command::Publish(msg))
+            .map_err(|_| BlueError::local_err("swarm is busy or stopped"))
+    }
+
+    /// Send the message to a single peer and wait for its response.
+    pub async fn send_to(&self, peer: PeerId, msg: M) -> BlueResult<M>
+    where
+        M: Serialize + DeserializeOwned,
+    {
+        let data = self.version.encode(&msg)?;
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::SendTo(peer, data, tx)).await?;
+        let response = rx.await.map_err(BlueError::local_err)??;
+
+        let (version, payload) = ProtocolVersion::decode(&response)
+            .ok_or_else(|| BlueError::remote_err("response without version"))?;
+        if !self.version.is_compatible(&version) {
+            return Err(BlueError::remote_err(format!(
+                "incompatible response version {}",
+                version
+            )));
+        }
+        rmp_serde::from_slice(payload).map_err(BlueError::remote_err)
+    }
+
+    /// Respond to the request received with `NetworkEvent::Request`.
+    pub async fn respond(&self, channel: ResponseChannelId, msg: M) -> BlueResult<()>
+    where
+        M: Serialize,
+    {
+        let data = self.version.encode(&msg)?;
+        self.send(Command::Respond(channel, data)).await
+    }
+
+    pub async fn connected_peers(&self) -> BlueResult<Vec<PeerId>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::ConnectedPeers(tx)).await?;
+        rx.await.map_err(BlueError::local_err)
+    }
+
+    /// Create another stream of the network events, only the events that happen after the
+    /// subscription are received.
+    pub async fn subscribe(&self) -> BlueResult<EventStream<M>> {
+        let (tx, rx) = event_queue(self.channel_size, self.overflow_policy);
+        self.send(Command::Subscribe(tx)).await?;
+        Ok(rx)
+    }
+
+    /// Say goodbye to the peers, close the connections and wait for the swarm to stop.
+    pub async fn shutdown(&self) -> BlueResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Command::Shutdown(tx)).await?;
+        rx.await.map_err(BlueError::local_err)
+    }
+
+    /// Wait for the swarm to stop. Resolves to the error the swarm failed with, e.g. when none
+    /// of the relays could be reached at startup, the event stream ends in that case as well.
+    pub async fn stopped(&self) -> BlueResult<()> {
+        let mut stopped = self.stopped.clone();
+        loop {
+            let result = stopped.borrow().clone();
+            if let Some(result) = result {
+                return result.map_err(BlueError::Local);
+            }
+            stopped
+                .changed()
+                .await
+                .map_err(|_| BlueError::local_err("swarm task was dropped"))?;
+        }
+    }
+
+    /// Table of the connection paths to the remote peers.
+    pub fn connection_table(&self) -> SharedConnectionTable {
+        self.connections.clone()
+    }
+
+    async fn send(&self, command: Command<M>) -> BlueResult<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| BlueError::local_err("swarm is stopped"))
+    }
+}
@@ -1,5 +1,21 @@
 mod behaviour;
+mod config;
+mod connection;
+mod direct;
+mod handle;
+mod queue;
+mod stats;
 mod swarm;
+mod validation;
+mod version;
 
 pub use behaviour::*;
+pub use config::*;
+pub use connection::*;
+pub use direct::*;
+pub use handle::*;
+pub use queue::*;
+pub use stats::*;
 pub use swarm::*;
+pub use validation::*;
+pub use version::*;
@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures::Stream;
+use tokio::sync::Notify;
+
+use crate::NetworkEvent;
+
+/// What to do with a network event when the game doesn't keep up and the event queue is full.
+/// Messages, stats and direct requests may be dropped, a dropped request times out on the
+/// sender side. Connection events are never dropped, the game relies on them to track the
+/// peers, they are queued over the capacity when there is nothing else to drop. They only
+/// happen when connections change, so the queue can't grow without bound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message.
+    DropOldest,
+    /// Replace the queued message from the same peer with the new one, useful when messages
+    /// carry the latest state, e.g. position. Falls back to dropping the oldest message.
+    CoalesceLatest,
+    /// Wait for the game to receive queued events, this stalls the whole swarm.
+    Block,
+}
+
+struct Inner<M> {
+    events: VecDeque<NetworkEvent<M>>,
+    waker: Option<Waker>,
+    sender_closed: bool,
+    receiver_closed: bool,
+}
+
+struct Shared<M> {
+    inner: Mutex<Inner<M>>,
+    space: Notify,
+    dropped: AtomicU64,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+/// Create a bounded queue of network events with the given overflow policy.
+pub(crate) fn event_queue<M>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (EventSender<M>, EventStream<M>) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            events: VecDeque::with_capacity(capacity),
+            waker: None,
+            sender_closed: false,
+            receiver_closed: false,
+        }),
+        space: Notify::new(),
+        dropped: AtomicU64::new(0),
+        capacity,
+        policy,
+    });
+
+    (
+        EventSender {
+            shared: shared.clone(),
+        },
+        EventStream { shared },
+    )
+}
+
+pub(crate) struct EventSender<M> {
+    shared: Arc<Shared<M>>,
+}
+
+impl<M> EventSender<M> {
+    pub fn is_closed(&self) -> bool {
+        self.shared
+            .inner
+            .lock()
+            .map(|inner| inner.receiver_closed)
+            .unwrap_or(true)
+    }
+
+    pub async fn send(&self, event: NetworkEvent<M>) {
+        let mut event = Some(event);
+        loop {
+            let space = self.shared.space.notified();
+            if let Some(e) = event.take() {
+                event = self.try_push(e);
+            }
+            if event.is_none() {
+                return;
+            }
+            space.await;
+        }
+    }
+
+    /// Push the event applying the overflow policy, the event is returned if it has to wait
+    /// for free space.
+    fn try_push(&self, event: NetworkEvent<M>) -> Option<NetworkEvent<M>> {
+        let mut inner = match self.shared.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return None,
+        };
+        if inner.receiver_closed {
+            return None;
+        }
+
+        if inner.events.len() >= self.shared.capacity {
+            let dropped = match self.shared.policy {
+                OverflowPolicy::Block => return Some(event),
+                OverflowPolicy::DropOldest => drop_oldest(&mut inner.events),
+                OverflowPolicy::CoalesceLatest => {
+                    coalesce(&mut inner.events, &event) || drop_oldest(&mut inner.events)
+                }
+            };
+            if dropped {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            } else if is_droppable(&event) {
+                // The new event is the oldest droppable one.
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        }
+
+        inner.events.push_back(event);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+
+        None
+    }
+}
+
+impl<M> Drop for EventSender<M> {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.shared.inner.lock() {
+            inner.sender_closed = true;
+            if let Some(waker) = inner.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+fn is_droppable<M>(event: &NetworkEvent<M>) -> bool {
+    matches!(
+        event,
+        NetworkEvent::Event(..) | NetworkEvent::Stats { .. } | NetworkEvent::Request { .. }
+    )
+}
+
+fn drop_oldest<M>(events: &mut VecDeque<NetworkEvent<M>>) -> bool {
+    match events.iter().position(is_droppable) {
+        Some(pos) => events.remove(pos).is_some(),
+        None => false,
+    }
+}
+
+/// Remove the queued message or stats of the same peer as the new event.
+fn coalesce<M>(events: &mut VecDeque<NetworkEvent<M>>, event: &NetworkEvent<M>) -> bool {
+    let pos = match event {
+        NetworkEvent::Event(peer, _) => events
+            .iter()
+            .position(|e| matches!(e, NetworkEvent::Event(p, _) if p == peer)),
+        NetworkEvent::Stats { peer, .. } => events
+            .iter()
+            .position(|e| matches!(e, NetworkEvent::Stats { peer: p, .. } if p == peer)),
+        _ => None,
+    };
+
+    match pos {
+        Some(pos) => events.remove(pos).is_some(),
+        None => false,
+    }
+}
+
+/// Stream of the network events, it ends when the swarm stops.
+pub struct EventStream<M> {
+    shared: Arc<Shared<M>>,
+}
+
+impl<M> EventStream<M> {
+    /// Non blocking receive, to be used from the game loop.
+    pub fn try_next(&mut self) -> Option<NetworkEvent<M>> {
+        let event = self.shared.inner.lock().ok()?.events.pop_front();
+        if event.is_some() {
+            self.shared.space.notify_one();
+        }
+        event
+    }
+
+    /// Number of events dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<M> Stream for EventStream<M> {
+    type Item = NetworkEvent<M>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut inner = match self.shared.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return Poll::Ready(None),
+        };
+
+        match inner.events.pop_front() {
+            Some(event) => {
+                self.shared.space.notify_one();
+                Poll::Ready(Some(event))
+            }
+            None if inner.sender_closed => Poll::Ready(None),
+            None => {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<M> Drop for EventStream<M> {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.shared.inner.lock() {
+            inner.receiver_closed = true;
+        }
+        // Unblock the swarm if it waits for the free space.
+        self.shared.space.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use libp2p::PeerId;
+
+    use super::*;
+
+    fn drain(events: &mut EventStream<u32>) -> Vec<(PeerId, u32)> {
+        std::iter::from_fn(|| events.try_next())
+            .map(|event| match event {
+                NetworkEvent::Event(peer, msg) => (peer, msg),
+                _ => panic!("unexpected event"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_latest_messages() {
+        let (tx, mut rx) = event_queue(2, OverflowPolicy::DropOldest);
+        let peer = PeerId::random();
+        for msg in 1..=3 {
+            tx.send(NetworkEvent::Event(peer, msg)).await;
+        }
+
+        assert_eq!(drain(&mut rx), vec![(peer, 2), (peer, 3)]);
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn coalesce_latest_replaces_message_of_same_peer() {
+        let (tx, mut rx) = event_queue(2, OverflowPolicy::CoalesceLatest);
+        let (a, b) = (PeerId::random(), PeerId::random());
+        tx.send(NetworkEvent::Event(a, 1)).await;
+        tx.send(NetworkEvent::Event(b, 1)).await;
+        tx.send(NetworkEvent::Event(a, 2)).await;
+
+        assert_eq!(drain(&mut rx), vec![(b, 1), (a, 2)]);
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn coalesce_latest_falls_back_to_drop_oldest() {
+        let (tx, mut rx) = event_queue(2, OverflowPolicy::CoalesceLatest);
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        tx.send(NetworkEvent::Event(a, 1)).await;
+        tx.send(NetworkEvent::Event(b, 1)).await;
+        tx.send(NetworkEvent::Event(c, 1)).await;
+
+        assert_eq!(drain(&mut rx), vec![(b, 1), (c, 1)]);
+        assert_eq!(rx.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_free_space() {
+        let (tx, mut rx) = event_queue(1, OverflowPolicy::Block);
+        let peer = PeerId::random();
+        tx.send(NetworkEvent::Event(peer, 1)).await;
+
+        let sender = tokio::spawn(async move {
+            tx.send(NetworkEvent::Event(peer, 2)).await;
+        });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!sender.is_finished());
+
+        assert_eq!(drain(&mut rx), vec![(peer, 1)]);
+        sender.await.unwrap();
+        assert_eq!(drain(&mut rx), vec![(peer, 2)]);
+        assert_eq!(rx.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn connection_events_are_never_dropped() {
+        let (tx, mut rx) = event_queue(1, OverflowPolicy::DropOldest);
+        let (a, b) = (PeerId::random(), PeerId::random());
+        tx.send(NetworkEvent::Event(a, 1)).await;
+        tx.send(NetworkEvent::Goodbye(a)).await;
+        tx.send(NetworkEvent::Goodbye(b)).await;
+        tx.send(NetworkEvent::Event(b, 1)).await;
+
+        assert!(matches!(rx.try_next(), Some(NetworkEvent::Goodbye(p)) if p == a));
+        assert!(matches!(rx.try_next(), Some(NetworkEvent::Goodbye(p)) if p == b));
+        assert!(rx.try_next().is_none());
+        assert_eq!(rx.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn stream_ends_when_sender_is_dropped() {
+        let (tx, mut rx) = event_queue::<u32>(1, OverflowPolicy::DropOldest);
+        tx.send(NetworkEvent::Goodbye(PeerId::random())).await;
+        drop(tx);
+
+        assert!(matches!(rx.next().await, Some(NetworkEvent::Goodbye(_))));
+        assert!(rx.next().await.is_none());
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Weight of the new loss sample, losses older than ~10 pings barely matter.
+const LOSS_WEIGHT: f32 = 0.1;
+
+/// Connection quality of the remote peer estimated from the pings.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerStats {
+    /// Round trip time of the last successful ping.
+    pub last_rtt: Duration,
+    /// Smoothed round trip time.
+    pub rtt: Duration,
+    /// Mean deviation of the consecutive round trip times.
+    pub jitter: Duration,
+    /// Estimated ratio of the lost pings, between 0 and 1.
+    pub loss: f32,
+}
+
+impl PeerStats {
+    /// Update the estimates with the successful ping, smoothing as in RFC 6298 and RFC 3550.
+    pub(crate) fn record_rtt(&mut self, rtt: Duration) {
+        if self.rtt.is_zero() {
+            self.rtt = rtt;
+        } else {
+            self.rtt = (self.rtt * 7 + rtt) / 8;
+            let deviation = if rtt > self.last_rtt {
+                rtt - self.last_rtt
+            } else {
+                self.last_rtt - rtt
+            };
+            self.jitter = if deviation > self.jitter {
+                self.jitter + (deviation - self.jitter) / 16
+            } else {
+                self.jitter - (self.jitter - deviation) / 16
+            };
+        }
+        self.last_rtt = rtt;
+        self.loss *= 1. - LOSS_WEIGHT;
+    }
+
+    pub(crate) fn record_loss(&mut self) {
+        self.loss = self.loss * (1. - LOSS_WEIGHT) + LOSS_WEIGHT;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn first_ping_sets_rtt() {
+        let mut stats = PeerStats::default();
+        stats.record_rtt(ms(100));
+        assert_eq!(stats.rtt, ms(100));
+        assert_eq!(stats.last_rtt, ms(100));
+        assert_eq!(stats.jitter, Duration::ZERO);
+    }
+
+    #[test]
+    fn rtt_and_jitter_are_smoothed() {
+        let mut stats = PeerStats::default();
+        stats.record_rtt(ms(100));
+        stats.record_rtt(ms(180));
+        assert_eq!(stats.rtt, ms(110));
+        assert_eq!(stats.last_rtt, ms(180));
+        assert_eq!(stats.jitter, ms(5));
+
+        // Jitter decreases when the round trip times settle.
+        stats.record_rtt(ms(180));
+        assert!(stats.jitter < ms(5));
+    }
+
+    #[test]
+    fn loss_decays_with_successful_pings() {
+        let mut stats = PeerStats::default();
+        stats.record_loss();
+        assert!((stats.loss - 0.1).abs() < 1e-6);
+        stats.record_loss();
+        assert!((stats.loss - 0.19).abs() < 1e-6);
+        stats.record_rtt(ms(100));
+        assert!((stats.loss - 0.171).abs() < 1e-6);
+    }
+}
@@ -1,41 +1,140 @@
 use common::*;
-use std::net::Ipv4Addr;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU8;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::{select, FutureExt, StreamExt};
+use libp2p::core::connection::ListenerId;
 use libp2p::core::multiaddr::{Multiaddr, Protocol};
 use libp2p::core::transport::OrTransport;
 use libp2p::core::upgrade;
 use libp2p::dns::DnsConfig;
-use libp2p::gossipsub::{GossipsubEvent, IdentTopic, Topic};
+use libp2p::gossipsub::{
+    GossipsubEvent, GossipsubMessage, IdentTopic, MessageId, Topic, TopicHash,
+};
 use libp2p::identify::{IdentifyEvent, IdentifyInfo};
+use libp2p::ping::{PingEvent, PingFailure, PingSuccess};
 use libp2p::relay::v2::client::Client;
+use libp2p::request_response::{
+    RequestId, RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+};
+use libp2p::swarm::dial_opts::DialOpts;
 use libp2p::swarm::SwarmEvent;
 use libp2p::tcp::{GenTcpConfig, TcpTransport};
-use libp2p::{core::transport, swarm::SwarmBuilder, PeerId};
+use libp2p::websocket::WsConfig;
+use libp2p::{core::transport, dcutr, swarm::SwarmBuilder, PeerId};
 use libp2p::{identity, noise, Transport};
 use libp2p_core::muxing::StreamMuxerBox;
 use log::info;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{oneshot, watch};
 
-use crate::Event;
+use crate::{
+    decode_gossip, event_queue, Command, ConnectionPath, ConnectionTable, Event, EventSender,
+    EventStream, MessageAcceptance, MessageValidator, PeerHandle, PeerStats, ProtocolVersion,
+    ResponseChannelId, SharedConnectionTable, SwarmConfig,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum NetworkEvent<M> {
-    NewConnection(String),
-    Event(String, M),
+    NewConnection(PeerId, ConnectionPath),
+    /// Message gossiped by the peer, the peer is the verified author of the message.
+    Event(PeerId, M),
+    /// Hole punching to the peer succeeded, `direct` is set when the peer is no longer reached
+    /// through the relay.
+    ConnectionUpgraded {
+        peer: PeerId,
+        direct: bool,
+    },
+    /// Hole punching to the peer failed, the relayed connection is kept.
+    HolePunchFailed {
+        peer: PeerId,
+        error: String,
+    },
+    /// Peer is shutting down and is about to close its connections.
+    Goodbye(PeerId),
+    /// Peer runs an incompatible version of the game, the connection to it is closed.
+    IncompatiblePeer {
+        peer: PeerId,
+        version: String,
+    },
+    /// Connection quality of the peer changed, sent after every ping.
+    Stats {
+        peer: PeerId,
+        stats: PeerStats,
+    },
+    /// Message sent directly to this peer, use `PeerHandle::respond` to answer it.
+    Request {
+        from: PeerId,
+        msg: M,
+        channel: ResponseChannelId,
+    },
 }
 
+/// Time given to the swarm to deliver the goodbye message before connections are closed.
+const GOODBYE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often lost relay reservations are replaced, every relay is tried at most once per interval.
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Circuit addresses are dialed one after another, so that the fastest relay is used when it's up.
+const SEQUENTIAL_DIAL: NonZeroU8 = match NonZeroU8::new(1) {
+    Some(n) => n,
+    None => unreachable!(),
+};
+
 type BBSwarm = libp2p::swarm::Swarm<crate::Behaviour>;
 
 pub struct Swarm {
     swarm: BBSwarm,
     origin: PeerId,
+    config: SwarmConfig,
+    /// Addresses of the relays from the endpoints and their federations, in order of preference.
+    relays: Vec<(PeerId, Multiaddr)>,
+    /// Relays the peer has reservation on, with the address and the listener of the reservation.
+    reserved: HashMap<PeerId, (Multiaddr, ListenerId)>,
+    /// Relay addresses tried since the last retry, so that unreachable relays aren't redialed
+    /// in a loop.
+    relay_attempts: HashSet<Multiaddr>,
+    /// Peers that run an incompatible version, they are disconnected as soon as they connect.
+    incompatible: HashSet<PeerId>,
+    /// Peers that are dialed directly, if dial fails, the circuit address will be used instead.
+    pending_direct: HashSet<PeerId>,
+    /// Peers that said goodbye, they are not redialed when their connection closes.
+    departed: HashSet<PeerId>,
+    /// Relays the peers are reserved on, peers missing here use our relays.
+    peer_relays: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Connection quality of the connected relays, it orders the relays to dial through.
+    relay_stats: HashMap<PeerId, PeerStats>,
+    connections: SharedConnectionTable,
+    /// Number of rejected messages propagated by the peer.
+    rejected: HashMap<PeerId, u32>,
+    /// Direct requests sent by this peer that wait for the response.
+    pending_requests: HashMap<RequestId, oneshot::Sender<BlueResult<Vec<u8>>>>,
+    /// Direct requests received from other peers that the game hasn't responded to yet, with
+    /// the time they were received.
+    inbound_requests: HashMap<ResponseChannelId, (Instant, ResponseChannel<Vec<u8>>)>,
+    next_channel_id: u64,
 }
 
 impl Swarm {
     pub async fn new_with_default_transport(local_key: identity::Keypair) -> BlueResult<Self> {
+        Self::new_with_config(local_key, SwarmConfig::default()).await
+    }
+
+    /// Same as default transport, but is also able to dial `/ws` addresses, e.g. relay
+    /// websocket listener.
+    pub async fn new_with_websocket_transport(local_key: identity::Keypair) -> BlueResult<Self> {
+        Self::new_with_config(local_key, SwarmConfig::default().with_websocket(true)).await
+    }
+
+    pub async fn new_with_config(
+        local_key: identity::Keypair,
+        config: SwarmConfig,
+    ) -> BlueResult<Self> {
         let local_peer_id = PeerId::from(local_key.public());
         let (relay_transport, client) = Client::new_transport_and_behaviour(local_peer_id);
 
@@ -43,117 +142,259 @@ impl Swarm {
             .into_authentic(&local_key)
             .expect("Signing libp2p-noise static DH keypair failed.");
 
-        let transport = OrTransport::new(
-            relay_transport,
+        let tcp_transport =
             DnsConfig::system(TcpTransport::new(GenTcpConfig::default().port_reuse(true)))
                 .await
-                .map_err(BlueError::local_err)?,
-        )
-        .upgrade(upgrade::Version::V1)
-        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-        .multiplex(libp2p_yamux::YamuxConfig::default())
-        .boxed();
+                .map_err(BlueError::local_err)?;
+
+        let transport = if config.websocket {
+            let ws_transport = WsConfig::new(
+                DnsConfig::system(TcpTransport::new(GenTcpConfig::default()))
+                    .await
+                    .map_err(BlueError::local_err)?,
+            );
 
-        let behaviour = crate::Behaviour::new(client, &local_key)?;
-        Self::try_new(transport, behaviour, local_peer_id)
+            OrTransport::new(
+                relay_transport,
+                OrTransport::new(ws_transport, tcp_transport),
+            )
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+            .multiplex(libp2p_yamux::YamuxConfig::default())
+            .timeout(config.connection_timeout)
+            .boxed()
+        } else {
+            OrTransport::new(relay_transport, tcp_transport)
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+                .multiplex(libp2p_yamux::YamuxConfig::default())
+                .timeout(config.connection_timeout)
+                .boxed()
+        };
+
+        let behaviour = crate::Behaviour::new(client, &local_key, &config)?;
+        Self::try_new(transport, behaviour, local_peer_id, config)
     }
 
     pub fn try_new(
         transport: transport::Boxed<(PeerId, StreamMuxerBox)>,
         behaviour: crate::Behaviour,
         peer_id: PeerId,
+        config: SwarmConfig,
     ) -> BlueResult<Self> {
         let swarm = SwarmBuilder::new(transport, behaviour, peer_id)
-            .dial_concurrency_factor(10_u8.try_into().map_err(BlueError::local_err)?)
+            .dial_concurrency_factor(
+                config
+                    .dial_concurrency_factor
+                    .try_into()
+                    .map_err(BlueError::local_err)?,
+            )
             .build();
         Ok(Self {
             swarm,
             origin: peer_id,
+            config,
+            relays: Vec::new(),
+            reserved: HashMap::new(),
+            relay_attempts: HashSet::new(),
+            incompatible: HashSet::new(),
+            pending_direct: HashSet::new(),
+            departed: HashSet::new(),
+            peer_relays: HashMap::new(),
+            relay_stats: HashMap::new(),
+            connections: Arc::new(Mutex::new(ConnectionTable::default())),
+            rejected: HashMap::new(),
+            pending_requests: HashMap::new(),
+            inbound_requests: HashMap::new(),
+            next_channel_id: 0,
         })
     }
 
-    pub async fn spawn<M>(
+    /// Connect to the relay and peers in the background, returns the handle to interact with
+    /// the swarm and the stream of network events. Has to be called within tokio runtime.
+    pub fn start<M>(self, base_url: url::Url) -> BlueResult<(PeerHandle<M>, EventStream<M>)>
+    where
+        M: Serialize + DeserializeOwned + Clone + Send + 'static,
+    {
+        self.start_with_relays(vec![base_url])
+    }
+
+    /// Same as `start`, but reserves on up to `SwarmConfig::max_relays` of the relays at once.
+    /// Peers are dialed through the relay with the lowest latency and lost reservations are
+    /// replaced by the remaining relays, including the ones federated with the given relays.
+    pub fn start_with_relays<M>(
+        self,
+        base_urls: Vec<url::Url>,
+    ) -> BlueResult<(PeerHandle<M>, EventStream<M>)>
+    where
+        M: Serialize + DeserializeOwned + Clone + Send + 'static,
+    {
+        self.spawn(base_urls, None)
+    }
+
+    /// Same as `start_with_relays`, received messages and direct requests are checked by the
+    /// application level validator before they are delivered to the game or propagated further.
+    pub fn start_with_validator<M, F>(
+        self,
+        base_urls: Vec<url::Url>,
+        validator: F,
+    ) -> BlueResult<(PeerHandle<M>, EventStream<M>)>
+    where
+        M: Serialize + DeserializeOwned + Clone + Send + 'static,
+        F: FnMut(&PeerId, &M) -> MessageAcceptance + Send + 'static,
+    {
+        self.spawn(base_urls, Some(Box::new(validator)))
+    }
+
+    fn spawn<M>(
+        mut self,
+        base_urls: Vec<url::Url>,
+        validator: Option<MessageValidator<M>>,
+    ) -> BlueResult<(PeerHandle<M>, EventStream<M>)>
+    where
+        M: Serialize + DeserializeOwned + Clone + Send + 'static,
+    {
+        let (commands_tx, commands_rx) = self.config.channel();
+        let (events_tx, events_rx) =
+            event_queue(self.config.channel_size, self.config.overflow_policy);
+        let (stopped_tx, stopped_rx) = watch::channel(None);
+        let handle = PeerHandle::new(
+            commands_tx,
+            self.connections.clone(),
+            stopped_rx,
+            self.config.channel_size,
+            self.config.overflow_policy,
+            self.config.protocol_version,
+        );
+
+        tokio::spawn(async move {
+            let res = self.run(base_urls, commands_rx, events_tx, validator).await;
+            match &res {
+                Ok(_) => info!("swarm stopped"),
+                Err(e) => log::error!("swarm failed: {:?}", e),
+            }
+            // The game learns about the failure from `PeerHandle::stopped`.
+            _ = stopped_tx.send(Some(res.map_err(|e| match e {
+                BlueError::Local(e) | BlueError::Remote(e) => e,
+                e => e.to_string(),
+            })));
+        });
+
+        Ok((handle, events_rx))
+    }
+
+    async fn run<M>(
         &mut self,
-        base_url: url::Url,
-        tx: Sender<NetworkEvent<M>>,
-        rx: Receiver<M>,
+        base_urls: Vec<url::Url>,
+        commands: Receiver<Command<M>>,
+        events: EventSender<M>,
+        validator: Option<MessageValidator<M>>,
     ) -> BlueResult<()>
     where
         M: Serialize + DeserializeOwned + Clone,
     {
         self.listen().await?;
 
-        let relay_info_url = base_url.join("/api/relay").map_err(BlueError::local_err)?;
-        let peers_info_url = base_url.join("/api/peers").map_err(BlueError::local_err)?;
+        let mut direct_addrs = HashMap::<PeerId, Vec<Multiaddr>>::new();
+        let mut federated_addrs = Vec::new();
+        for base_url in base_urls.iter() {
+            let (relay_info, peer_info) = match fetch_relay(base_url).await {
+                Ok(info) => info,
+                Err(e) => {
+                    info!("skipping relay {}: {:?}", base_url, e);
+                    continue;
+                }
+            };
+
+            let relay_addrs = relay_info.addrs();
+            for addr in relay_addrs.iter() {
+                self.add_relay(addr.clone());
+            }
+            federated_addrs.extend(relay_info.federated_addrs());
 
-        let relay_info = reqwest::get(relay_info_url)
-            .await
-            .map_err(|e| BlueError::local_err(format!("relay info err {:?}", e)))?
-            .json::<WebRelayInfo>()
-            .await
-            .map_err(BlueError::local_err)?;
+            for peer in peer_info.iter() {
+                let peer_id = match PeerId::from_str(&peer.addr) {
+                    Ok(peer_id) => peer_id,
+                    Err(e) => {
+                        info!("skipping peer {}: {:?}", peer.addr, e);
+                        continue;
+                    }
+                };
+                extend_unique(
+                    direct_addrs.entry(peer_id).or_default(),
+                    peer.addrs
+                        .iter()
+                        .filter_map(|addr| Multiaddr::from_str(addr).ok()),
+                );
 
-        let peer_info = reqwest::get(peers_info_url)
-            .await
-            .map_err(|e| BlueError::local_err(format!("peer info err {:?}", e)))?
-            .json::<Vec<WebPeerInfo>>()
-            .await
-            .map_err(BlueError::local_err)?;
+                // Peers listed without relay addresses are reserved on the listing relay.
+                let peer_relay_addrs = if peer.relay_addrs.is_empty() {
+                    relay_addrs.clone()
+                } else {
+                    peer.relay_addrs
+                        .iter()
+                        .filter_map(|addr| Multiaddr::from_str(addr).ok())
+                        .collect()
+                };
+                // The relay might have joined the federation after `/api/relay` was fetched,
+                // it has to be known as a relay before the peer is dialed through it.
+                federated_addrs.extend(peer_relay_addrs.iter().cloned());
+                extend_unique(
+                    self.peer_relays.entry(peer_id).or_default(),
+                    peer_relay_addrs,
+                );
+            }
+        }
 
-        let mut relay_address = Multiaddr::empty();
-        let mut connected = false;
+        // Federated relays are used only when the given ones are not enough.
+        for addr in federated_addrs {
+            self.add_relay(addr);
+        }
 
-        for ip in relay_info.ips.iter() {
-            let relay_address_str = format!("{}/p2p/{}", ip, relay_info.peer_id);
-            relay_address =
-                Multiaddr::from_str(&relay_address_str).map_err(BlueError::local_err)?;
+        // Relays tell the peer its public address, it's needed for the hole punching.
+        for (relay, relay_address) in self.relays.clone() {
+            if self.reserved.len() >= self.config.max_relays {
+                break;
+            }
+            if self.reserved.contains_key(&relay) {
+                continue;
+            }
 
-            info!("trying addr: {:?}", &relay_address_str);
-            match self.observe_addr(relay_address.clone()).await {
+            info!("trying addr: {:?}", relay_address);
+            match self.observe_addr(relay, relay_address.clone()).await {
                 Ok(_) => {
-                    info!("connected to: {}", relay_address_str);
-                    connected = true;
-                    break;
-                }
-                Err(_) => {
-                    info!("failed to connect to {}", relay_address_str);
-                    continue;
+                    info!("connected to: {}", relay_address);
+                    self.listen_on_relay(relay, relay_address)?;
                 }
+                Err(_) => info!("failed to connect to {}", relay_address),
             }
         }
 
-        info!("addr: {:?}", &relay_address);
-
-        if !connected {
+        if self.reserved.is_empty() {
             return Err(BlueError::local_err("Unable to connect to relay"));
         }
 
-        self.listen_on_relay(relay_address.clone())?;
-        for peer in peer_info.iter() {
-            _ = self.dial(
-                &relay_address,
-                PeerId::from_str(&peer.addr).map_err(BlueError::local_err)?,
-            );
+        for (peer_id, direct_addrs) in direct_addrs {
+            if peer_id != self.origin {
+                _ = self.dial(peer_id, direct_addrs);
+            }
         }
 
-        self.spawn_event_loop(tx, rx).await;
+        self.spawn_event_loop(commands, vec![events], validator)
+            .await;
 
         Ok(())
     }
 
+    /// Table of the connection paths to the remote peers, it's updated while the swarm is running.
+    pub fn connection_table(&self) -> SharedConnectionTable {
+        self.connections.clone()
+    }
+
     async fn listen(&mut self) -> BlueResult<()> {
-        self.swarm
-            .listen_on(
-                Multiaddr::empty()
-                    .with(
-                        "0.0.0.0"
-                            .parse::<Ipv4Addr>()
-                            .map_err(BlueError::local_err)?
-                            .into(),
-                    )
-                    .with(Protocol::Tcp(0)),
-            )
-            .map_err(BlueError::local_err)?;
+        for addr in self.config.listen_addrs.clone() {
+            self.swarm.listen_on(addr).map_err(BlueError::local_err)?;
+        }
 
         let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
         loop {
@@ -176,7 +417,7 @@ impl Swarm {
         Ok(())
     }
 
-    async fn observe_addr(&mut self, relay_address: Multiaddr) -> BlueResult<()> {
+    async fn observe_addr(&mut self, relay: PeerId, relay_address: Multiaddr) -> BlueResult<()> {
         self.swarm
             .dial(relay_address.clone())
             .map_err(BlueError::local_err)?;
@@ -194,14 +435,16 @@ impl Swarm {
                 SwarmEvent::NewListenAddr { .. } => {}
                 SwarmEvent::Dialing { .. } => {}
                 SwarmEvent::ConnectionEstablished { .. } => {}
-                SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Sent { .. })) => {
+                SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Sent { peer_id }))
+                    if peer_id == relay =>
+                {
                     info!("Told relay its public address.");
                     told_relay_observed_addr = true;
                 }
                 SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received {
+                    peer_id,
                     info: IdentifyInfo { observed_addr, .. },
-                    ..
-                })) => {
+                })) if peer_id == relay => {
                     info!("Relay told us our public address: {:?}", observed_addr);
                     learned_observed_addr = true;
                 }
@@ -219,52 +462,190 @@ impl Swarm {
         Ok(())
     }
 
-    fn dial(&mut self, addr: &Multiaddr, remote_peer_id: PeerId) -> BlueResult<()> {
+    /// Dial remote peer directly if it has public addresses, otherwise dial through the relay.
+    fn dial(&mut self, remote_peer_id: PeerId, direct_addrs: Vec<Multiaddr>) -> BlueResult<()> {
+        let direct_addrs = direct_addrs
+            .into_iter()
+            .filter(is_public_addr)
+            .collect::<Vec<Multiaddr>>();
+
+        if direct_addrs.is_empty() {
+            return self.dial_relayed(remote_peer_id);
+        }
+
+        info!("dialing {} directly via {:?}", remote_peer_id, direct_addrs);
+        self.pending_direct.insert(remote_peer_id);
         self.swarm
             .dial(
-                addr.clone()
-                    .with(Protocol::P2pCircuit)
-                    .with(Protocol::P2p(remote_peer_id.into())),
+                DialOpts::peer_id(remote_peer_id)
+                    .addresses(direct_addrs)
+                    .build(),
             )
             .map_err(BlueError::local_err)?;
 
         Ok(())
     }
 
-    fn listen_on_relay(&mut self, relay_address: Multiaddr) -> BlueResult<()> {
-        info!("relay_addr: {}", relay_address);
+    /// Dial the remote peer through its relays, starting with the one of the lowest latency.
+    /// Relays this peer is reserved on are tried after them, the remote peer might have failed
+    /// over to them.
+    fn dial_relayed(&mut self, remote_peer_id: PeerId) -> BlueResult<()> {
+        let mut relay_addrs = self
+            .peer_relays
+            .get(&remote_peer_id)
+            .cloned()
+            .unwrap_or_default();
+        let mut reserved_addrs = self
+            .reserved
+            .values()
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<Multiaddr>>();
+        self.sort_by_latency(&mut relay_addrs);
+        self.sort_by_latency(&mut reserved_addrs);
+        extend_unique(&mut relay_addrs, reserved_addrs);
+        if relay_addrs.is_empty() {
+            return Err(BlueError::local_err("not connected to relay"));
+        }
+
+        let circuit_addrs = relay_addrs
+            .into_iter()
+            .map(|addr| {
+                addr.with(Protocol::P2pCircuit)
+                    .with(Protocol::P2p(remote_peer_id.into()))
+            })
+            .collect();
         self.swarm
-            .listen_on(relay_address.with(Protocol::P2pCircuit))
+            .dial(
+                DialOpts::peer_id(remote_peer_id)
+                    .addresses(circuit_addrs)
+                    .override_dial_concurrency_factor(SEQUENTIAL_DIAL)
+                    .build(),
+            )
+            .map_err(BlueError::local_err)?;
+
+        Ok(())
+    }
+
+    /// Relays that weren't pinged yet go last.
+    fn sort_by_latency(&self, relay_addrs: &mut [Multiaddr]) {
+        relay_addrs.sort_by_key(|addr| {
+            relay_peer_id(addr)
+                .and_then(|relay| self.relay_stats.get(&relay))
+                .map(|stats| stats.rtt)
+                .unwrap_or(Duration::MAX)
+        });
+    }
+
+    fn listen_on_relay(&mut self, relay: PeerId, relay_address: Multiaddr) -> BlueResult<()> {
+        info!("relay_addr: {}", relay_address);
+        let listener = self
+            .swarm
+            .listen_on(relay_address.clone().with(Protocol::P2pCircuit))
             .map_err(BlueError::local_err)?;
+        self.reserved.insert(relay, (relay_address, listener));
 
         Ok(())
     }
 
+    /// Remember the relay address, addresses without the relay peer id are ignored.
+    fn add_relay(&mut self, relay_address: Multiaddr) {
+        if let Some(relay) = relay_peer_id(&relay_address) {
+            let relay = (relay, relay_address);
+            if !self.relays.contains(&relay) {
+                self.relays.push(relay);
+            }
+        }
+    }
+
+    fn is_relay(&self, peer_id: &PeerId) -> bool {
+        self.relays.iter().any(|(relay, _)| relay == peer_id)
+    }
+
+    /// Reserve on the next relays until there are `max_relays` reservations.
+    fn reserve_relays(&mut self) {
+        for (relay, relay_address) in self.relays.clone() {
+            if self.reserved.len() >= self.config.max_relays {
+                break;
+            }
+            if self.reserved.contains_key(&relay)
+                || !self.relay_attempts.insert(relay_address.clone())
+            {
+                continue;
+            }
+            if let Err(e) = self.listen_on_relay(relay, relay_address) {
+                info!("failed to reserve on relay {}: {:?}", relay, e);
+            }
+        }
+    }
+
+    /// Forget the reservation on the relay and fail over to the next relays. The relay isn't
+    /// tried again before the next retry.
+    fn lose_relay(&mut self, relay: PeerId) {
+        if let Some((relay_address, listener)) = self.reserved.remove(&relay) {
+            info!("lost reservation on {}", relay_address);
+            _ = self.swarm.remove_listener(listener);
+            for (_, relay_address) in self.relays.iter().filter(|(id, _)| *id == relay) {
+                self.relay_attempts.insert(relay_address.clone());
+            }
+            self.reserve_relays();
+        }
+    }
+
     async fn spawn_event_loop<M>(
         &mut self,
-        remote_in: Sender<NetworkEvent<M>>,
-        mut local_out: Receiver<M>,
+        mut commands: Receiver<Command<M>>,
+        mut subscribers: Vec<EventSender<M>>,
+        mut validator: Option<MessageValidator<M>>,
     ) where
         M: Serialize + DeserializeOwned + Clone,
     {
-        let stream = async_stream::stream! {
-            while let Some(item) = local_out.recv().await {
-                yield item;
-            }
-        };
-        let stream = stream.fuse();
-
-        tokio::pin!(stream);
+        let mut shutdown_ack = None;
+        let mut relay_retry = futures_timer::Delay::new(RELAY_RETRY_INTERVAL).fuse();
+        let mut request_expiry = futures_timer::Delay::new(self.config.request_timeout).fuse();
 
         loop {
             select! {
-                msg = stream.select_next_some() => {
-                    let msg = NetworkEvent::Event(self.origin.to_string(), msg);
-                    let msg = rmp_serde::to_vec(&msg).unwrap();
-                    _ = self.swarm
-                        .behaviour_mut()
-                        .gossip
-                        .publish(IdentTopic::new(self.origin.to_string()), msg);
+                _ = relay_retry => {
+                    self.relay_attempts.clear();
+                    self.reserve_relays();
+                    relay_retry = futures_timer::Delay::new(RELAY_RETRY_INTERVAL).fuse();
+                }
+                _ = request_expiry => {
+                    self.expire_requests();
+                    request_expiry = futures_timer::Delay::new(self.config.request_timeout).fuse();
+                }
+                command = commands.recv().fuse() => match command {
+                    Some(Command::Publish(msg)) => {
+                        let msg = NetworkEvent::Event(self.origin, msg);
+                        match self.config.protocol_version.encode(&msg) {
+                            Ok(msg) => {
+                                _ = self.swarm
+                                    .behaviour_mut()
+                                    .gossip
+                                    .publish(IdentTopic::new(self.origin.to_string()), msg);
+                            }
+                            Err(e) => info!("failed to encode message: {:?}", e),
+                        }
+                    }
+                    Some(Command::SendTo(peer, data, tx)) => {
+                        let request_id = self.swarm.behaviour_mut().direct.send_request(&peer, data);
+                        self.pending_requests.insert(request_id, tx);
+                    }
+                    Some(Command::Respond(channel, data)) => {
+                        if let Some((_, channel)) = self.inbound_requests.remove(&channel) {
+                            _ = self.swarm.behaviour_mut().direct.send_response(channel, data);
+                        }
+                    }
+                    Some(Command::ConnectedPeers(tx)) => {
+                        _ = tx.send(self.swarm.connected_peers().cloned().collect());
+                    }
+                    Some(Command::Subscribe(tx)) => subscribers.push(tx),
+                    Some(Command::Shutdown(ack)) => {
+                        shutdown_ack = Some(ack);
+                        break;
+                    }
+                    // All handles are dropped, nothing to do anymore.
+                    None => break,
                 },
                 event = self.swarm.select_next_some() => match event {
                     SwarmEvent::NewListenAddr { address, .. } => {
@@ -274,44 +655,487 @@ impl Swarm {
                         info!("{:?}", event)
                     }
                     SwarmEvent::Behaviour(Event::Dcutr(event)) => {
-                        info!("{:?}", event)
+                        info!("{:?}", event);
+                        match event {
+                            dcutr::behaviour::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                                if let Ok(mut connections) = self.connections.lock() {
+                                    connections.hole_punch_succeeded += 1;
+                                    connections.set(remote_peer_id, ConnectionPath::Direct);
+                                }
+                                notify(&mut subscribers, NetworkEvent::ConnectionUpgraded {
+                                    peer: remote_peer_id,
+                                    direct: true,
+                                }).await;
+                            }
+                            dcutr::behaviour::Event::DirectConnectionUpgradeFailed { remote_peer_id, error } => {
+                                if let Ok(mut connections) = self.connections.lock() {
+                                    connections.hole_punch_failed += 1;
+                                }
+                                notify(&mut subscribers, NetworkEvent::HolePunchFailed {
+                                    peer: remote_peer_id,
+                                    error: format!("{:?}", error),
+                                }).await;
+                            }
+                            _ => {}
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received { peer_id, info })) => {
+                        if let Some(event) = self.check_version(peer_id, &info.protocol_version) {
+                            notify(&mut subscribers, event).await;
+                        }
                     }
                     SwarmEvent::Behaviour(Event::Identify(event)) => {
                         info!("{:?}", event)
                     }
+                    SwarmEvent::Behaviour(Event::Ping(event)) => {
+                        if let Some(event) = self.record_ping(event) {
+                            notify(&mut subscribers, event).await;
+                        }
+                    }
+                    SwarmEvent::Behaviour(Event::Direct(event)) => {
+                        if let Some(request) = self.handle_direct_event(validator.as_mut(), event) {
+                            notify(&mut subscribers, request).await;
+                        }
+                    }
                     SwarmEvent::Behaviour(Event::Gossipsub(GossipsubEvent::Message {
-                        propagation_source: _peer_id,
-                        message_id: _id,
+                        propagation_source,
+                        message_id,
+                        message,
+                    })) if message.topic == relay_shutdown_topic().hash() => {
+                        self.handle_relay_shutdown(propagation_source, message_id, message);
+                    }
+                    SwarmEvent::Behaviour(Event::Gossipsub(GossipsubEvent::Message {
+                        propagation_source,
+                        message_id,
                         message,
                     })) => {
-                        let msg: NetworkEvent<M> = rmp_serde::from_slice(&message.data).unwrap();
-                        _ = remote_in.send(msg.clone()).await;
+                        if let Some(msg) = self.validate_message(validator.as_mut(), propagation_source, message_id, message) {
+                            notify(&mut subscribers, msg).await;
+                        }
                     },
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } if self.incompatible.contains(&peer_id) => {
+                        _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } if self.is_relay(&peer_id) => {
+                        info!("Established connection to relay {:?} via {:?}", peer_id, endpoint);
+                    }
                     SwarmEvent::ConnectionEstablished {
                         peer_id, endpoint, ..
                     } => {
+                        let path = ConnectionPath::from(&endpoint);
+                        self.pending_direct.remove(&peer_id);
+                        if let Ok(mut connections) = self.connections.lock() {
+                            connections.insert(peer_id, path);
+                        }
+
                         let topic: IdentTopic = Topic::new(peer_id.to_string());
                         _ = self.swarm.behaviour_mut().gossip.subscribe(&topic);
-                        _ = remote_in.send(NetworkEvent::NewConnection(peer_id.to_string())).await;
-                        info!("Established connection to {:?} via {:?}", peer_id, endpoint);
+                        notify(&mut subscribers, NetworkEvent::NewConnection(peer_id, path)).await;
+                        info!("Established {:?} connection to {:?} via {:?}", path, peer_id, endpoint);
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                        let path = self.connections.lock().ok().and_then(|mut connections| {
+                            let path = connections.get(&peer_id);
+                            connections.remove(&peer_id);
+                            path
+                        });
+                        let departed = self.departed.remove(&peer_id);
+                        if self.is_relay(&peer_id) {
+                            self.relay_stats.remove(&peer_id);
+                            self.lose_relay(peer_id);
+                        } else if path == Some(ConnectionPath::Relayed)
+                            && !departed
+                            && !self.incompatible.contains(&peer_id)
+                        {
+                            // The relay of the circuit is likely gone, reconnect through the
+                            // remaining ones.
+                            info!("relayed connection to {} closed, redialing", peer_id);
+                            _ = self.dial_relayed(peer_id);
+                        }
+                    }
+                    SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                        let relay = self
+                            .reserved
+                            .iter()
+                            .find(|(_, (_, listener))| *listener == listener_id)
+                            .map(|(relay, _)| *relay);
+                        if let Some(relay) = relay {
+                            info!("reservation on relay {} closed: {:?}", relay, reason);
+                            self.lose_relay(relay);
+                        }
                     }
                     SwarmEvent::OutgoingConnectionError { peer_id, error } => {
                         info!("Outgoing connection error to {:?}: {:?}", peer_id, error);
+                        if let Some(peer_id) = peer_id {
+                            if self.pending_direct.remove(&peer_id) {
+                                info!("falling back to relayed connection to {}", peer_id);
+                                _ = self.dial_relayed(peer_id);
+                            }
+                        }
                     }
                     _ => {}
                 }
             }
         }
+
+        self.shutdown::<M>().await;
+        if let Some(ack) = shutdown_ack {
+            _ = ack.send(());
+        }
+    }
+
+    /// Decode and validate the gossiped message, the verdict is reported back to gossipsub so
+    /// that only valid messages are propagated further.
+    fn validate_message<M>(
+        &mut self,
+        validator: Option<&mut MessageValidator<M>>,
+        propagation_source: PeerId,
+        message_id: MessageId,
+        message: GossipsubMessage,
+    ) -> Option<NetworkEvent<M>>
+    where
+        M: DeserializeOwned,
+    {
+        let author = message.source;
+        let (acceptance, msg) =
+            match decode_gossip(self.config.protocol_version, author, &message.data) {
+                Ok(NetworkEvent::Event(origin, msg)) => {
+                    let acceptance = validator
+                        .map(|validator| validator(&origin, &msg))
+                        .unwrap_or(MessageAcceptance::Accept);
+                    (acceptance, Some(NetworkEvent::Event(origin, msg)))
+                }
+                Ok(event) => {
+                    if let NetworkEvent::Goodbye(origin) = &event {
+                        self.departed.insert(*origin);
+                    }
+                    (MessageAcceptance::Accept, Some(event))
+                }
+                Err(acceptance) => (acceptance, None),
+            };
+
+        let accepted = matches!(acceptance, MessageAcceptance::Accept);
+        if matches!(acceptance, MessageAcceptance::Reject) {
+            info!("rejected message {:?} from {:?}", message_id, author);
+            self.penalize(propagation_source);
+        }
+
+        _ = self
+            .swarm
+            .behaviour_mut()
+            .gossip
+            .report_message_validation_result(&message_id, &propagation_source, acceptance);
+
+        msg.filter(|_| accepted)
+    }
+
+    /// Lower the gossipsub score of the peer for every rejected message it sent.
+    fn penalize(&mut self, peer: PeerId) {
+        let rejected = self.rejected.entry(peer).or_default();
+        *rejected += 1;
+        self.swarm
+            .behaviour_mut()
+            .gossip
+            .set_application_score(&peer, -(*rejected as f64));
+    }
+
+    /// Move the reservation away from the relay that is shutting down. Announcements of unknown
+    /// relays are ignored, they might be federated relays this peer hasn't heard of.
+    fn handle_relay_shutdown(
+        &mut self,
+        propagation_source: PeerId,
+        message_id: MessageId,
+        message: GossipsubMessage,
+    ) {
+        let acceptance = match message.source.filter(|relay| self.is_relay(relay)) {
+            Some(relay) => {
+                info!("relay {} is shutting down", relay);
+                self.lose_relay(relay);
+                MessageAcceptance::Accept
+            }
+            None => MessageAcceptance::Ignore,
+        };
+
+        _ = self
+            .swarm
+            .behaviour_mut()
+            .gossip
+            .report_message_validation_result(&message_id, &propagation_source, acceptance);
+    }
+
+    /// Refuse the peer if it runs an incompatible version, relays are not game peers.
+    fn check_version<M>(
+        &mut self,
+        peer_id: PeerId,
+        protocol_version: &str,
+    ) -> Option<NetworkEvent<M>> {
+        if self.is_relay(&peer_id) {
+            return None;
+        }
+
+        let compatible = ProtocolVersion::parse(protocol_version)
+            .map(|version| self.config.protocol_version.is_compatible(&version))
+            .unwrap_or(false);
+        if compatible || !self.incompatible.insert(peer_id) {
+            return None;
+        }
+
+        info!(
+            "refusing {} with incompatible version {}",
+            peer_id, protocol_version
+        );
+        let gossip = &mut self.swarm.behaviour_mut().gossip;
+        gossip.blacklist_peer(&peer_id);
+        _ = gossip.unsubscribe(&IdentTopic::new(peer_id.to_string()));
+        _ = self.swarm.disconnect_peer_id(peer_id);
+
+        Some(NetworkEvent::IncompatiblePeer {
+            peer: peer_id,
+            version: protocol_version.to_string(),
+        })
+    }
+
+    /// Update the connection quality of the peer with the ping result. Relays are kept apart,
+    /// they are not game peers.
+    fn record_ping<M>(&mut self, event: PingEvent) -> Option<NetworkEvent<M>> {
+        if self.is_relay(&event.peer) {
+            record_ping_result(
+                self.relay_stats.entry(event.peer).or_default(),
+                event.result,
+            );
+            return None;
+        }
+
+        let mut connections = self.connections.lock().ok()?;
+        let stats = connections.stats_mut(event.peer);
+        if !record_ping_result(stats, event.result) {
+            return None;
+        }
+
+        Some(NetworkEvent::Stats {
+            peer: event.peer,
+            stats: *stats,
+        })
+    }
+
+    /// Resolve pending requests with the responses, decode and validate the requests for the
+    /// game. Requests that are not delivered are dropped, the sender gets an error.
+    fn handle_direct_event<M>(
+        &mut self,
+        validator: Option<&mut MessageValidator<M>>,
+        event: RequestResponseEvent<Vec<u8>, Vec<u8>>,
+    ) -> Option<NetworkEvent<M>>
+    where
+        M: DeserializeOwned,
+    {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                let msg = ProtocolVersion::decode(&request)
+                    .filter(|(version, _)| self.config.protocol_version.is_compatible(version))
+                    .and_then(|(_, payload)| rmp_serde::from_slice(payload).ok());
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        info!("invalid request from {}", peer);
+                        return None;
+                    }
+                };
+                match validator.map(|validator| validator(&peer, &msg)) {
+                    Some(MessageAcceptance::Reject) => {
+                        info!("rejected request from {}", peer);
+                        self.penalize(peer);
+                        return None;
+                    }
+                    Some(MessageAcceptance::Ignore) => return None,
+                    Some(MessageAcceptance::Accept) | None => {}
+                }
+
+                let id = ResponseChannelId(self.next_channel_id);
+                self.next_channel_id += 1;
+                self.inbound_requests.insert(id, (Instant::now(), channel));
+
+                Some(NetworkEvent::Request {
+                    from: peer,
+                    msg,
+                    channel: id,
+                })
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                if let Some(tx) = self.pending_requests.remove(&request_id) {
+                    _ = tx.send(Ok(response));
+                }
+                None
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if let Some(tx) = self.pending_requests.remove(&request_id) {
+                    _ = tx.send(Err(BlueError::remote_err(format!(
+                        "request to {} failed: {:?}",
+                        peer, error
+                    ))));
+                }
+                None
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                info!("request from {} failed: {:?}", peer, error);
+                None
+            }
+            RequestResponseEvent::ResponseSent { .. } => None,
+        }
+    }
+
+    /// Forget the requests the game didn't respond to in time, the sender gave up on them
+    /// already, and the ones whose connection is gone.
+    fn expire_requests(&mut self) {
+        let timeout = self.config.request_timeout;
+        self.inbound_requests
+            .retain(|_, (received, channel)| received.elapsed() < timeout && channel.is_open());
+    }
+
+    /// Say goodbye to the peers, leave all topics and close the connections.
+    async fn shutdown<M>(&mut self)
+    where
+        M: Serialize,
+    {
+        info!("shutting down swarm");
+        let goodbye = self
+            .config
+            .protocol_version
+            .encode(&NetworkEvent::<M>::Goodbye(self.origin));
+        let gossip = &mut self.swarm.behaviour_mut().gossip;
+        if let Ok(msg) = goodbye {
+            _ = gossip.publish(IdentTopic::new(self.origin.to_string()), msg);
+        }
+
+        let topics = gossip.topics().cloned().collect::<Vec<TopicHash>>();
+        for topic in topics {
+            _ = gossip.unsubscribe(&IdentTopic::new(topic.as_str()));
+        }
+
+        let mut delay = futures_timer::Delay::new(GOODBYE_TIMEOUT).fuse();
+        loop {
+            select! {
+                _ = self.swarm.select_next_some() => {},
+                _ = delay => break,
+            }
+        }
+
+        let peers = self
+            .swarm
+            .connected_peers()
+            .cloned()
+            .collect::<Vec<PeerId>>();
+        for peer in peers {
+            _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+}
+
+async fn fetch_relay(base_url: &url::Url) -> BlueResult<(WebRelayInfo, Vec<WebPeerInfo>)> {
+    let relay_info_url = base_url.join("/api/relay").map_err(BlueError::local_err)?;
+    let peers_info_url = base_url.join("/api/peers").map_err(BlueError::local_err)?;
+
+    let relay_info = reqwest::get(relay_info_url)
+        .await
+        .map_err(|e| BlueError::local_err(format!("relay info err {:?}", e)))?
+        .json::<WebRelayInfo>()
+        .await
+        .map_err(BlueError::local_err)?;
+
+    let peer_info = reqwest::get(peers_info_url)
+        .await
+        .map_err(|e| BlueError::local_err(format!("peer info err {:?}", e)))?
+        .json::<Vec<WebPeerInfo>>()
+        .await
+        .map_err(BlueError::local_err)?;
+
+    Ok((relay_info, peer_info))
+}
+
+fn extend_unique(addrs: &mut Vec<Multiaddr>, new_addrs: impl IntoIterator<Item = Multiaddr>) {
+    for addr in new_addrs {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+}
+
+/// Update the stats with the ping result, returns whether the result was a ping.
+fn record_ping_result(stats: &mut PeerStats, result: Result<PingSuccess, PingFailure>) -> bool {
+    match result {
+        Ok(PingSuccess::Ping { rtt }) => stats.record_rtt(rtt),
+        Err(PingFailure::Timeout) | Err(PingFailure::Other { .. }) => stats.record_loss(),
+        Ok(PingSuccess::Pong) | Err(PingFailure::Unsupported) => return false,
+    }
+    true
+}
+
+/// Peer id the address ends with, relay addresses always include it.
+fn relay_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+}
+
+/// Deliver the event to all subscribers, subscribers that dropped their stream are removed.
+async fn notify<M>(subscribers: &mut Vec<EventSender<M>>, event: NetworkEvent<M>)
+where
+    M: Clone,
+{
+    subscribers.retain(|tx| !tx.is_closed());
+    for tx in subscribers.iter() {
+        tx.send(event.clone()).await;
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct WebPeerInfo {
     addr: String,
+    #[serde(default)]
+    addrs: Vec<String>,
+    #[serde(default)]
+    relay_addrs: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct WebRelayInfo {
     peer_id: String,
     ips: Vec<String>,
+    #[serde(default)]
+    federated: Vec<String>,
+}
+
+impl WebRelayInfo {
+    /// Addresses of the relay including its peer id.
+    fn addrs(&self) -> Vec<Multiaddr> {
+        self.ips
+            .iter()
+            .filter_map(|ip| Multiaddr::from_str(&format!("{}/p2p/{}", ip, self.peer_id)).ok())
+            .collect()
+    }
+
+    /// Addresses of the relays federated with the relay, including their peer ids.
+    fn federated_addrs(&self) -> Vec<Multiaddr> {
+        self.federated
+            .iter()
+            .filter_map(|addr| Multiaddr::from_str(addr).ok())
+            .collect()
+    }
 }
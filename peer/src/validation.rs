@@ -0,0 +1,160 @@
+use libp2p::PeerId;
+use serde::de::DeserializeOwned;
+
+use crate::{NetworkEvent, ProtocolVersion};
+
+pub use libp2p::gossipsub::MessageAcceptance;
+
+/// Application level check of a received message, called with the message author.
+/// Rejected messages are not delivered to the game and lower the score of the peer that
+/// propagated them, peers with a low score are eventually graylisted by the mesh.
+pub type MessageValidator<M> = Box<dyn FnMut(&PeerId, &M) -> MessageAcceptance + Send>;
+
+/// Decode the gossiped message of the signed `author`. Only game events and goodbyes sent by
+/// their author are returned, otherwise the verdict for gossipsub is.
+pub(crate) fn decode_gossip<M>(
+    local_version: ProtocolVersion,
+    author: Option<PeerId>,
+    data: &[u8],
+) -> Result<NetworkEvent<M>, MessageAcceptance>
+where
+    M: DeserializeOwned,
+{
+    let (version, payload) = match ProtocolVersion::decode(data) {
+        Some((version, _)) if !local_version.is_compatible(&version) => (version, None),
+        Some((version, payload)) => (version, Some(payload)),
+        None => (local_version, None),
+    };
+
+    // The source is signed by the author, so it can't be forged unlike the payload.
+    match payload.map(rmp_serde::from_slice::<NetworkEvent<M>>) {
+        Some(Ok(NetworkEvent::Event(origin, msg))) if Some(origin) == author => {
+            Ok(NetworkEvent::Event(origin, msg))
+        }
+        Some(Ok(NetworkEvent::Goodbye(origin))) if Some(origin) == author => {
+            Ok(NetworkEvent::Goodbye(origin))
+        }
+        // Newer peer might send messages this version doesn't know about.
+        Some(Err(_)) if version.minor > local_version.minor => Err(MessageAcceptance::Ignore),
+        // Only game events are gossiped, anything else is either forged or malformed.
+        Some(Ok(_)) | Some(Err(_)) => Err(MessageAcceptance::Reject),
+        // Incompatible peers are refused after identify, don't penalize them in the meantime.
+        None if version != local_version => Err(MessageAcceptance::Ignore),
+        None => Err(MessageAcceptance::Reject),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    enum Message {
+        Move(f32),
+    }
+
+    #[derive(Serialize, Deserialize, Clone)]
+    enum NewerMessage {
+        Move(f32),
+        Trade(u32),
+    }
+
+    const LOCAL: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+    fn encode<M: Serialize>(version: ProtocolVersion, event: NetworkEvent<M>) -> Vec<u8> {
+        version.encode(&event).unwrap()
+    }
+
+    fn is_rejected(author: Option<PeerId>, data: &[u8]) -> bool {
+        matches!(
+            decode_gossip::<Message>(LOCAL, author, data),
+            Err(MessageAcceptance::Reject)
+        )
+    }
+
+    fn is_ignored(author: Option<PeerId>, data: &[u8]) -> bool {
+        matches!(
+            decode_gossip::<Message>(LOCAL, author, data),
+            Err(MessageAcceptance::Ignore)
+        )
+    }
+
+    #[test]
+    fn accepts_event_of_its_author() {
+        let author = PeerId::random();
+        let data = encode(LOCAL, NetworkEvent::Event(author, Message::Move(1.)));
+        match decode_gossip::<Message>(LOCAL, Some(author), &data) {
+            Ok(NetworkEvent::Event(origin, msg)) => {
+                assert_eq!(origin, author);
+                assert_eq!(msg, Message::Move(1.));
+            }
+            _ => panic!("event of the author was not accepted"),
+        }
+
+        let data = encode(LOCAL, NetworkEvent::<Message>::Goodbye(author));
+        assert!(matches!(
+            decode_gossip::<Message>(LOCAL, Some(author), &data),
+            Ok(NetworkEvent::Goodbye(origin)) if origin == author
+        ));
+    }
+
+    #[test]
+    fn rejects_forged_origin() {
+        let (author, victim) = (PeerId::random(), PeerId::random());
+        let event = encode(LOCAL, NetworkEvent::Event(victim, Message::Move(1.)));
+        let goodbye = encode(LOCAL, NetworkEvent::<Message>::Goodbye(victim));
+        for data in [event, goodbye] {
+            assert!(is_rejected(Some(author), &data));
+            assert!(is_rejected(None, &data));
+        }
+    }
+
+    #[test]
+    fn rejects_events_that_are_not_gossiped() {
+        let author = PeerId::random();
+        let data = encode(
+            LOCAL,
+            NetworkEvent::<Message>::IncompatiblePeer {
+                peer: author,
+                version: LOCAL.to_string(),
+            },
+        );
+        assert!(is_rejected(Some(author), &data));
+    }
+
+    #[test]
+    fn unknown_variant_is_ignored_only_from_newer_minor() {
+        let author = PeerId::random();
+        let event = NetworkEvent::Event(author, NewerMessage::Trade(1));
+
+        let known = encode(
+            ProtocolVersion::new(1, 1),
+            NetworkEvent::Event(author, NewerMessage::Move(1.)),
+        );
+        assert!(matches!(
+            decode_gossip::<Message>(LOCAL, Some(author), &known),
+            Ok(NetworkEvent::Event(_, Message::Move(_)))
+        ));
+
+        let newer = encode(ProtocolVersion::new(1, 1), event.clone());
+        assert!(is_ignored(Some(author), &newer));
+
+        let same = encode(LOCAL, event);
+        assert!(is_rejected(Some(author), &same));
+    }
+
+    #[test]
+    fn incompatible_version_is_ignored() {
+        let author = PeerId::random();
+        let data = encode(
+            ProtocolVersion::new(2, 0),
+            NetworkEvent::Event(author, Message::Move(1.)),
+        );
+        assert!(is_ignored(Some(author), &data));
+
+        // Too short for the version tag.
+        assert!(is_rejected(Some(author), &[1, 0]));
+    }
+}
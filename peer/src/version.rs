@@ -0,0 +1,134 @@
+use std::fmt;
+
+use common::{BlueError, BlueResult};
+use serde::Serialize;
+
+/// Prefix of the identify protocol version announced by peers.
+const PROTOCOL_PREFIX: &str = "/beyond-blue/";
+
+/// Version of the game message schema. Peers with a different major version can't understand
+/// each other, newer minor versions may only add optional fields and new message variants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::new(1, 0)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}.{}", PROTOCOL_PREFIX, self.major, self.minor)
+    }
+}
+
+impl ProtocolVersion {
+    pub fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+
+    /// Parse the version announced with identify protocol.
+    pub fn parse(protocol_version: &str) -> Option<Self> {
+        let (major, minor) = protocol_version
+            .strip_prefix(PROTOCOL_PREFIX)?
+            .split_once('.')?;
+        Some(Self::new(major.parse().ok()?, minor.parse().ok()?))
+    }
+
+    /// Encode the message as msgpack with field names, so that older peers can skip the fields
+    /// they don't know, prefixed with the version tag.
+    pub(crate) fn encode<T>(&self, msg: &T) -> BlueResult<Vec<u8>>
+    where
+        T: Serialize,
+    {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.major.to_be_bytes());
+        data.extend_from_slice(&self.minor.to_be_bytes());
+        rmp_serde::encode::write_named(&mut data, msg).map_err(BlueError::local_err)?;
+        Ok(data)
+    }
+
+    /// Split the version tag from the payload.
+    pub(crate) fn decode(data: &[u8]) -> Option<(Self, &[u8])> {
+        if data.len() < 4 {
+            return None;
+        }
+        let (tag, payload) = data.split_at(4);
+        let version = Self::new(
+            u16::from_be_bytes([tag[0], tag[1]]),
+            u16::from_be_bytes([tag[2], tag[3]]),
+        );
+        Some((version, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct MoveV2 {
+        x: f32,
+        y: f32,
+        speed: f32,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct MoveV1 {
+        x: f32,
+        y: f32,
+    }
+
+    #[test]
+    fn parse_announced_version() {
+        let version = ProtocolVersion::new(2, 3);
+        assert_eq!(version.to_string(), "/beyond-blue/2.3");
+        assert_eq!(ProtocolVersion::parse(&version.to_string()), Some(version));
+        assert_eq!(ProtocolVersion::parse("/ipfs/0.1.0"), None);
+        assert_eq!(ProtocolVersion::parse("/beyond-blue/2"), None);
+    }
+
+    #[test]
+    fn only_major_version_has_to_match() {
+        let version = ProtocolVersion::new(1, 0);
+        assert!(version.is_compatible(&ProtocolVersion::new(1, 5)));
+        assert!(!version.is_compatible(&ProtocolVersion::new(2, 0)));
+    }
+
+    #[test]
+    fn decode_splits_version_tag() {
+        let version = ProtocolVersion::new(1, 2);
+        let data = version.encode(&"hello").unwrap();
+        let (decoded, payload) = ProtocolVersion::decode(&data).unwrap();
+        assert_eq!(decoded, version);
+        assert_eq!(rmp_serde::from_slice::<String>(payload).unwrap(), "hello");
+
+        assert!(ProtocolVersion::decode(&[0, 1, 0]).is_none());
+    }
+
+    #[test]
+    fn older_peer_skips_new_fields() {
+        let data = ProtocolVersion::new(1, 1)
+            .encode(&MoveV2 {
+                x: 1.,
+                y: 2.,
+                speed: 3.,
+            })
+            .unwrap();
+        let (_, payload) = ProtocolVersion::decode(&data).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<MoveV1>(payload).unwrap(),
+            MoveV1 { x: 1., y: 2. }
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use actix_web::dev::ServiceRequest;
+use libp2p::relay::v2::relay;
+use libp2p::swarm::SwarmEvent;
+use libp2p::PeerId;
+use tracing::info;
+
+use crate::Event;
+
+/// Target of the audit events, they are written to the audit file next to the regular log.
+pub const AUDIT_TARGET: &str = "bb_relay::audit";
+
+/// Log who connected to the relay, reserved on it and was relayed to whom.
+pub(crate) fn record_swarm<THandleErr>(event: &SwarmEvent<Event, THandleErr>)
+where
+    THandleErr: fmt::Debug,
+{
+    match event {
+        SwarmEvent::ConnectionEstablished {
+            peer_id, endpoint, ..
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "connection_established",
+            peer = %peer_id,
+            addr = %endpoint.get_remote_address(),
+        ),
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            endpoint,
+            cause,
+            ..
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "connection_closed",
+            peer = %peer_id,
+            addr = %endpoint.get_remote_address(),
+            error = ?cause,
+        ),
+        SwarmEvent::BannedPeer { peer_id, endpoint } => info!(
+            target: AUDIT_TARGET,
+            event = "connection_denied",
+            peer = %peer_id,
+            addr = %endpoint.get_remote_address(),
+        ),
+        SwarmEvent::Behaviour(Event::Relay(event)) => record_relay(event),
+        _ => {}
+    }
+}
+
+fn record_relay(event: &relay::Event) {
+    match event {
+        relay::Event::ReservationReqAccepted {
+            src_peer_id,
+            renewed,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "reservation_accepted",
+            peer = %src_peer_id,
+            renewed,
+        ),
+        relay::Event::ReservationReqAcceptFailed { src_peer_id, error } => info!(
+            target: AUDIT_TARGET,
+            event = "reservation_accept_failed",
+            peer = %src_peer_id,
+            error = ?error,
+        ),
+        relay::Event::ReservationReqDenied { src_peer_id } => info!(
+            target: AUDIT_TARGET,
+            event = "reservation_denied",
+            peer = %src_peer_id,
+        ),
+        relay::Event::ReservationReqDenyFailed { src_peer_id, error } => info!(
+            target: AUDIT_TARGET,
+            event = "reservation_deny_failed",
+            peer = %src_peer_id,
+            error = ?error,
+        ),
+        relay::Event::ReservationTimedOut { src_peer_id } => info!(
+            target: AUDIT_TARGET,
+            event = "reservation_timed_out",
+            peer = %src_peer_id,
+        ),
+        relay::Event::CircuitReqReceiveFailed { src_peer_id, error } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_request_failed",
+            peer = %src_peer_id,
+            error = ?error,
+        ),
+        relay::Event::CircuitReqDenied {
+            src_peer_id,
+            dst_peer_id,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_denied",
+            peer = %src_peer_id,
+            dst_peer = %dst_peer_id,
+        ),
+        relay::Event::CircuitReqDenyFailed {
+            src_peer_id,
+            dst_peer_id,
+            error,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_deny_failed",
+            peer = %src_peer_id,
+            dst_peer = %dst_peer_id,
+            error = ?error,
+        ),
+        relay::Event::CircuitReqAccepted {
+            src_peer_id,
+            dst_peer_id,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_accepted",
+            peer = %src_peer_id,
+            dst_peer = %dst_peer_id,
+        ),
+        relay::Event::CircuitReqOutboundConnectFailed {
+            src_peer_id,
+            dst_peer_id,
+            error,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_connect_failed",
+            peer = %src_peer_id,
+            dst_peer = %dst_peer_id,
+            error = ?error,
+        ),
+        relay::Event::CircuitReqAcceptFailed {
+            src_peer_id,
+            dst_peer_id,
+            error,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_accept_failed",
+            peer = %src_peer_id,
+            dst_peer = %dst_peer_id,
+            error = ?error,
+        ),
+        relay::Event::CircuitClosed {
+            src_peer_id,
+            dst_peer_id,
+            error,
+        } => info!(
+            target: AUDIT_TARGET,
+            event = "circuit_closed",
+            peer = %src_peer_id,
+            dst_peer = %dst_peer_id,
+            error = ?error,
+        ),
+    }
+}
+
+/// Log the moderation of the peer, e.g. `kicked` or `banned`.
+pub(crate) fn record_admin(action: &'static str, peer: &PeerId) {
+    info!(target: AUDIT_TARGET, event = "admin", action, peer = %peer);
+}
+
+/// Request to the HTTP API, logged once the response is sent.
+pub struct HttpAccess {
+    method: String,
+    path: String,
+    client: Option<IpAddr>,
+    start: Instant,
+}
+
+impl HttpAccess {
+    pub fn new(req: &ServiceRequest) -> Self {
+        Self {
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            client: req.peer_addr().map(|addr| addr.ip()),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(self, status: u16) {
+        info!(
+            target: AUDIT_TARGET,
+            event = "http_request",
+            method = %self.method,
+            path = %self.path,
+            client = ?self.client,
+            status,
+            duration_ms = self.start.elapsed().as_millis() as u64,
+        );
+    }
+}
@@ -1,73 +1,199 @@
+use actix_web::dev::Service;
 use actix_web::rt::signal;
 use actix_web::{web, App, HttpServer};
 use clap::Parser;
 use common::BlueError;
-use libp2p::multiaddr::Protocol;
-use libp2p::Multiaddr;
-use relay::{api_config, MemoryPeerStore, SharedStore};
+use relay::{
+    admin_config, api_config, init_logging, AdminState, FilePeerStore, HttpAccess, HttpRateLimiter,
+    Limits, MemoryPeerStore, Metrics, Settings, SharedMetrics, SharedRateLimiter, SharedStore,
+    SharedTraffic, StoreBackend, Traffic,
+};
 use std::error::Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 #[clap(name = "libp2p relay")]
 struct Opt {
-    /// Determine if the relay listen on ipv6 or ipv4 loopback address. the default is ipv4
+    /// TOML config file, values can be overridden with `BB_RELAY_*` environment variables
     #[clap(long)]
-    use_ipv6: Option<bool>,
+    config: Option<PathBuf>,
 
-    /// Fixed value to generate deterministic peer id
+    /// The port used to listen on all IPv4 and IPv6 interfaces, replaces configured addresses
     #[clap(long)]
-    secret_key_seed: u8,
+    swarm_port: Option<u16>,
 
-    /// The port used to listen on all interfaces
+    /// The port used to serve http api
     #[clap(long)]
-    swarm_port: u16,
+    http_port: Option<u16>,
 
-    /// The port used to serve http api
+    /// The port used to listen for websocket connections on all interfaces
+    #[clap(long)]
+    ws_port: Option<u16>,
+
+    /// Public address of the relay advertised to the peers, can be repeated
+    #[clap(long = "external-address")]
+    external_addrs: Vec<String>,
+
+    /// Advertise private listen addresses while there is no public one, for local setups
     #[clap(long)]
-    http_port: u16,
+    advertise_private_addrs: bool,
+
+    #[clap(flatten)]
+    limits: Limits,
+
+    /// Deprecated and ignored, the key was never derived from it, set `identity` instead
+    #[clap(long, hide = true)]
+    secret_key_seed: Option<u8>,
+
+    /// Deprecated and ignored, `--swarm-port` listens on both IPv4 and IPv6
+    #[clap(long, hide = true)]
+    use_ipv6: Option<bool>,
+}
+
+impl Opt {
+    /// Command line options take precedence over the config file and the environment.
+    fn settings(&self) -> Result<Settings, BlueError> {
+        let mut settings = Settings::load(self.config.as_deref())?;
+        if let Some(port) = self.swarm_port {
+            settings = settings.with_swarm_port(port);
+        }
+        if let Some(port) = self.ws_port {
+            settings = settings.with_ws_port(port);
+        }
+        if let Some(port) = self.http_port {
+            settings = settings.with_http_port(port);
+        }
+        settings = settings.with_external_addrs(self.external_addrs.clone());
+        settings.advertise_private_addrs |= self.advertise_private_addrs;
+        settings.limits = settings.limits.merge(self.limits.clone());
+        Ok(settings)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
     let opt = Opt::parse();
+    let config_path = opt.config.clone();
+    let settings = opt.settings()?;
+    // Flushes the audit file on exit.
+    let _audit_guard = init_logging(&settings)?;
+    if opt.secret_key_seed.is_some() {
+        tracing::warn!("--secret-key-seed is deprecated and ignored, set `identity` instead");
+    }
+    if opt.use_ipv6.is_some() {
+        tracing::warn!("--use-ipv6 is deprecated and ignored, the relay listens on IPv4 and IPv6");
+    }
 
-    let store: SharedStore = Arc::new(Mutex::new(MemoryPeerStore::default()));
+    let config = settings.limits.relay_config();
+    let store: SharedStore = match settings.store {
+        StoreBackend::Memory => Arc::new(Mutex::new(MemoryPeerStore::default())),
+        StoreBackend::File => Arc::new(Mutex::new(FilePeerStore::open(
+            settings.store_path.clone(),
+        )?)),
+    };
+    let stored_bans = store.lock().map_err(BlueError::local_err)?.banned();
+    let metrics: SharedMetrics = Arc::new(Metrics::default());
+    let traffic: SharedTraffic = Arc::new(Traffic::new(config.traffic_quota));
+    let rate_limiter: SharedRateLimiter =
+        Arc::new(Mutex::new(HttpRateLimiter::new(config.http_rate_per_ip)));
+
+    let id = match &settings.identity {
+        Some(path) => common::Identity::load_or_generate(path)?,
+        None => common::Identity::from_file("nothing".into()),
+    };
+    let mut swarm = relay::Swarm::new_with_default_transport(
+        id.get_key(),
+        store.clone(),
+        metrics.clone(),
+        traffic.clone(),
+        &config,
+    )
+    .await?;
 
-    let id = common::Identity::from_file("nothing".into());
-    let mut swarm = relay::Swarm::new_with_default_transport(id.get_key(), store.clone()).await?;
+    for addr in settings.external_addrs()? {
+        swarm.add_external_address(addr)?;
+    }
+    swarm.advertise_private_addrs(settings.advertise_private_addrs)?;
+    for (peer, addr) in settings.autonat_servers()? {
+        swarm.add_autonat_server(peer, Some(addr));
+    }
+    let config_bans = settings.banned_peers()?;
+    for peer in stored_bans.into_iter().chain(config_bans.iter().cloned()) {
+        swarm.ban(peer)?;
+    }
+    swarm.set_shutdown_timeout(Duration::from_secs(settings.shutdown_timeout_secs));
+    let shutdown = swarm.admin_handle();
 
-    let listen_addr = Multiaddr::empty()
-        .with(match opt.use_ipv6 {
-            Some(true) => Protocol::from(Ipv6Addr::UNSPECIFIED),
-            _ => Protocol::from(Ipv4Addr::UNSPECIFIED),
-        })
-        .with(Protocol::Tcp(opt.swarm_port));
+    let admin = web::Data::new(AdminState {
+        token: settings.admin_token.clone(),
+        handle: swarm.admin_handle(),
+        config_path: config_path.clone(),
+        config_bans: Mutex::new(config_bans.into_iter().collect()),
+        rate_limiter: rate_limiter.clone(),
+    });
 
-    let swarm = tokio::spawn(async move {
-        swarm.listen_on(listen_addr).await?;
+    let health = swarm.health();
+    let federation_peers = settings.federation_peers()?;
+    let listen_addrs = settings.listen_addrs()?;
+    let mut swarm = tokio::spawn(async move {
+        for addr in listen_addrs {
+            swarm.listen_on(addr).await?;
+        }
+        for (peer, addr) in federation_peers {
+            swarm.add_federation_peer(peer, addr)?;
+        }
         swarm.spawn().await?;
         Ok::<(), BlueError>(())
     });
 
-    let http_api = HttpServer::new(move || {
+    let mut http_api = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(store.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(traffic.clone()))
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(web::Data::new(health.clone()))
+            .app_data(admin.clone())
+            .wrap_fn(|req, srv| {
+                let access = HttpAccess::new(&req);
+                let res = srv.call(req);
+                async move {
+                    let res = res.await?;
+                    access.record(res.status().as_u16());
+                    Ok(res)
+                }
+            })
             .configure(api_config)
+            .configure(admin_config)
     })
-    .bind(("0.0.0.0", opt.http_port))?
+    // Ctrl-c is handled below, so that the swarm is shut down before the HTTP API.
+    .disable_signals()
+    .shutdown_timeout(settings.shutdown_timeout_secs)
+    .bind(settings.http_bind)?
     .run();
+    let http_handle = http_api.handle();
 
-    let server = async {
-        _ = tokio::join!(swarm, http_api);
-    };
-
+    // Exit with an error if the swarm fails, so that the relay gets restarted instead of serving
+    // stale data.
     tokio::select! {
-        _ = server => {},
-        _ = signal::ctrl_c() => {},
+        result = &mut http_api => {
+            tracing::info!("HTTP API stopped, shutting down");
+            shutdown.shutdown().await?;
+            swarm.await??;
+            return Ok(result?);
+        }
+        result = &mut swarm => result??,
+        _ = signal::ctrl_c() => {
+            tracing::info!("shutting down");
+            // The HTTP API keeps running meanwhile, `/ready` reports the relay is draining.
+            shutdown.shutdown().await?;
+            swarm.await??;
+        }
     }
 
-    Ok(())
+    // The server has to be polled to finish the in-flight requests.
+    let (_, result) = tokio::join!(http_handle.stop(true), http_api);
+    Ok(result?)
 }
@@ -0,0 +1,167 @@
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use common::{BlueError, BlueResult};
+use libp2p::relay::v2::relay::{self, rate_limiter};
+
+use crate::TrafficQuota;
+
+/// At most `limit` requests are allowed within every `interval`.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub interval: Duration,
+}
+
+impl RateLimit {
+    pub fn new(limit: u32, interval: Duration) -> Self {
+        Self { limit, interval }
+    }
+
+    pub fn per_minute(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(60))
+    }
+
+    fn to_libp2p(self) -> BlueResult<rate_limiter::GenericRateLimiterConfig> {
+        Ok(rate_limiter::GenericRateLimiterConfig {
+            limit: NonZeroU32::new(self.limit)
+                .ok_or_else(|| BlueError::local_err("rate limit has to be positive"))?,
+            interval: self.interval,
+        })
+    }
+}
+
+/// Resource limits of the relay, defaults are the same as the ones of libp2p.
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Number of reservations kept by the relay.
+    pub max_reservations: usize,
+
+    /// Number of reservations kept for a single peer.
+    pub max_reservations_per_peer: usize,
+
+    /// How long the reservation lasts before it has to be renewed.
+    pub reservation_duration: Duration,
+
+    pub reservation_rate_per_peer: RateLimit,
+    pub reservation_rate_per_ip: RateLimit,
+
+    /// Number of circuits relayed at once.
+    pub max_circuits: usize,
+
+    /// Number of circuits relayed for a single peer.
+    pub max_circuits_per_peer: usize,
+
+    /// Circuit is closed after this time, peers are expected to upgrade to direct connection.
+    pub max_circuit_duration: Duration,
+
+    /// Circuit is closed after relaying this many bytes.
+    pub max_circuit_bytes: u64,
+
+    pub circuit_rate_per_peer: RateLimit,
+    pub circuit_rate_per_ip: RateLimit,
+
+    /// Requests to the HTTP API allowed from a single IP address.
+    pub http_rate_per_ip: RateLimit,
+
+    /// Bytes relayed for a single peer, peers over the quota are denied new reservations and
+    /// circuits. Unlimited by default.
+    pub traffic_quota: Option<TrafficQuota>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: Duration::from_secs(60 * 60),
+            reservation_rate_per_peer: RateLimit::new(30, Duration::from_secs(60 * 2)),
+            reservation_rate_per_ip: RateLimit::per_minute(60),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1 << 17,
+            circuit_rate_per_peer: RateLimit::new(30, Duration::from_secs(60 * 2)),
+            circuit_rate_per_ip: RateLimit::per_minute(60),
+            http_rate_per_ip: RateLimit::per_minute(120),
+            traffic_quota: None,
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn with_max_reservations(mut self, max: usize) -> Self {
+        self.max_reservations = max;
+        self
+    }
+
+    pub fn with_max_reservations_per_peer(mut self, max: usize) -> Self {
+        self.max_reservations_per_peer = max;
+        self
+    }
+
+    pub fn with_reservation_duration(mut self, duration: Duration) -> Self {
+        self.reservation_duration = duration;
+        self
+    }
+
+    pub fn with_reservation_rate_per_ip(mut self, rate: RateLimit) -> Self {
+        self.reservation_rate_per_ip = rate;
+        self
+    }
+
+    pub fn with_max_circuits(mut self, max: usize) -> Self {
+        self.max_circuits = max;
+        self
+    }
+
+    pub fn with_max_circuits_per_peer(mut self, max: usize) -> Self {
+        self.max_circuits_per_peer = max;
+        self
+    }
+
+    pub fn with_max_circuit_duration(mut self, duration: Duration) -> Self {
+        self.max_circuit_duration = duration;
+        self
+    }
+
+    pub fn with_max_circuit_bytes(mut self, bytes: u64) -> Self {
+        self.max_circuit_bytes = bytes;
+        self
+    }
+
+    pub fn with_circuit_rate_per_ip(mut self, rate: RateLimit) -> Self {
+        self.circuit_rate_per_ip = rate;
+        self
+    }
+
+    pub fn with_http_rate_per_ip(mut self, rate: RateLimit) -> Self {
+        self.http_rate_per_ip = rate;
+        self
+    }
+
+    pub fn with_traffic_quota(mut self, quota: TrafficQuota) -> Self {
+        self.traffic_quota = Some(quota);
+        self
+    }
+
+    pub(crate) fn to_libp2p(&self) -> BlueResult<relay::Config> {
+        Ok(relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: self.reservation_duration,
+            reservation_rate_limiters: vec![
+                rate_limiter::new_per_peer(self.reservation_rate_per_peer.to_libp2p()?),
+                rate_limiter::new_per_ip(self.reservation_rate_per_ip.to_libp2p()?),
+            ],
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: self.max_circuit_duration,
+            max_circuit_bytes: self.max_circuit_bytes,
+            circuit_src_rate_limiters: vec![
+                rate_limiter::new_per_peer(self.circuit_rate_per_peer.to_libp2p()?),
+                rate_limiter::new_per_ip(self.circuit_rate_per_ip.to_libp2p()?),
+            ],
+        })
+    }
+}
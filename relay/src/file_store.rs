@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use common::{BlueError, BlueResult};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::{MemoryPeerStore, PeerInfo, PeerStore, RelayInfo};
+
+/// Content of the store file.
+#[derive(Serialize, Deserialize, Default)]
+struct StoreFile {
+    banned: Vec<String>,
+}
+
+/// Peer store that keeps the banned peers in a JSON file, so that the bans survive restarts of
+/// the relay. Peers and their addresses are kept only in memory, they reconnect after a restart.
+pub struct FilePeerStore {
+    path: PathBuf,
+    memory: MemoryPeerStore,
+    banned: HashSet<PeerId>,
+    /// Bans changed since the last flush.
+    dirty: bool,
+}
+
+impl FilePeerStore {
+    /// Read the store file, a missing file is an empty store.
+    pub fn open(path: PathBuf) -> BlueResult<Self> {
+        let file = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<StoreFile>(&data).map_err(BlueError::local_err)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => StoreFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let banned = file
+            .banned
+            .iter()
+            .map(|peer| PeerId::from_str(peer).map_err(BlueError::local_err))
+            .collect::<BlueResult<HashSet<PeerId>>>()?;
+
+        Ok(Self {
+            path,
+            memory: MemoryPeerStore::default(),
+            banned,
+            dirty: false,
+        })
+    }
+}
+
+impl PeerStore for FilePeerStore {
+    fn add(&mut self, peer: PeerId) {
+        self.memory.add(peer);
+    }
+
+    fn get_all(&self) -> Vec<PeerInfo> {
+        self.memory.get_all()
+    }
+
+    fn get_local(&self) -> Vec<PeerInfo> {
+        self.memory.get_local()
+    }
+
+    fn remove(&mut self, peer: PeerId) {
+        self.memory.remove(peer);
+    }
+
+    fn set_peer_addrs(&mut self, peer: PeerId, addrs: Vec<String>) {
+        self.memory.set_peer_addrs(peer, addrs);
+    }
+
+    fn set_relay_peer_id(&mut self, peer: &PeerId) {
+        self.memory.set_relay_peer_id(peer);
+    }
+
+    fn append_relay_addr(&mut self, addr: String) {
+        self.memory.append_relay_addr(addr);
+    }
+
+    fn add_relay_external_addr(&mut self, addr: String) {
+        self.memory.add_relay_external_addr(addr);
+    }
+
+    fn set_relay_confirmed_addr(&mut self, addr: Option<String>) {
+        self.memory.set_relay_confirmed_addr(addr);
+    }
+
+    fn set_relay_advertise_private_addrs(&mut self, advertise: bool) {
+        self.memory.set_relay_advertise_private_addrs(advertise);
+    }
+
+    fn get_relay(&self) -> RelayInfo {
+        self.memory.get_relay()
+    }
+
+    fn set_remote_peers(&mut self, relay: PeerId, relay_addrs: Vec<String>, peers: Vec<PeerInfo>) {
+        self.memory.set_remote_peers(relay, relay_addrs, peers);
+    }
+
+    fn expire_remote_peers(&mut self, max_age: Duration) {
+        self.memory.expire_remote_peers(max_age);
+    }
+
+    fn ban(&mut self, peer: PeerId) {
+        self.dirty |= self.banned.insert(peer);
+    }
+
+    fn unban(&mut self, peer: &PeerId) {
+        self.dirty |= self.banned.remove(peer);
+    }
+
+    fn banned(&self) -> Vec<PeerId> {
+        self.banned.iter().cloned().collect()
+    }
+
+    /// Write the bans to a temporary file first, so that a crash doesn't leave a partial file.
+    fn flush(&mut self) -> BlueResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let file = StoreFile {
+            banned: self.banned.iter().map(PeerId::to_string).collect(),
+        };
+        let data = serde_json::to_vec_pretty(&file).map_err(BlueError::local_err)?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn bans_survive_reopen() {
+        let path = env::temp_dir().join(format!("bb-relay-store-{}.json", std::process::id()));
+        let (kept, unbanned) = (PeerId::random(), PeerId::random());
+
+        let mut store = FilePeerStore::open(path.clone()).unwrap();
+        assert!(store.banned().is_empty());
+        store.ban(kept);
+        store.ban(unbanned);
+        store.unban(&unbanned);
+        store.flush().unwrap();
+
+        let store = FilePeerStore::open(path.clone());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(store.unwrap().banned(), vec![kept]);
+    }
+}
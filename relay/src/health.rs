@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub type SharedHealth = Arc<Health>;
+
+/// Liveness of the swarm, updated by the swarm and read by the HTTP API.
+#[derive(Default, Debug)]
+pub struct Health {
+    running: AtomicBool,
+    listeners: AtomicUsize,
+    draining: AtomicBool,
+}
+
+impl Health {
+    /// Swarm event loop is running.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Number of addresses the swarm is listening on.
+    pub fn listeners(&self) -> usize {
+        self.listeners.load(Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_running(&self, running: bool) {
+        self.running.store(running, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_listeners(&self, listeners: usize) {
+        self.listeners.store(listeners, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+}
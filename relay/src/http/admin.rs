@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use actix_web::http::header;
+use actix_web::{error, web, HttpRequest, HttpResponse, Responder, Result};
+use libp2p::PeerId;
+use serde::Serialize;
+
+use super::rate_limit::limit_rate;
+use crate::{AdminHandle, Settings, SharedRateLimiter, SharedTraffic};
+
+/// Everything the admin endpoints need, registered as app data by `bb-relay`.
+pub struct AdminState {
+    /// Bearer token of the admin, admin endpoints are disabled without it.
+    pub token: Option<String>,
+    pub handle: AdminHandle,
+    /// Config file re-read on reload.
+    pub config_path: Option<PathBuf>,
+    /// Peers banned by `banned_peers` of the config file, reload unbans only the ones removed
+    /// from it.
+    pub config_bans: Mutex<HashSet<PeerId>>,
+    pub rate_limiter: SharedRateLimiter,
+}
+
+impl AdminState {
+    fn authorize(&self, req: &HttpRequest) -> Result<()> {
+        let token = self
+            .token
+            .as_deref()
+            .ok_or_else(|| error::ErrorForbidden("admin api is disabled"))?;
+        let provided = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| error::ErrorUnauthorized("missing token"))?;
+
+        if constant_time_eq(token.as_bytes(), provided.as_bytes()) {
+            Ok(())
+        } else {
+            Err(error::ErrorUnauthorized("invalid token"))
+        }
+    }
+}
+
+/// Compare without returning early, so that the token can't be guessed from the response time.
+/// Lengths are compared the same way, shorter input is padded with zeros.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let len = a.len().max(b.len());
+    let diff = (0..len).fold(a.len() ^ b.len(), |acc, i| {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        acc | usize::from(x ^ y)
+    });
+    diff == 0
+}
+
+pub fn admin_config(app: &mut web::ServiceConfig) {
+    app.service(
+        web::scope("/admin")
+            // Same limit as the public API, so that the token can't be brute forced.
+            .wrap_fn(limit_rate)
+            .route("/peers/{id}", web::delete().to(kick_peer))
+            .route("/bans", web::get().to(get_bans))
+            .route("/bans/{id}", web::put().to(ban_peer))
+            .route("/bans/{id}", web::delete().to(unban_peer))
+            .route("/stats", web::get().to(get_stats))
+            .route("/quota/{id}", web::delete().to(reset_quota))
+            .route("/drain", web::put().to(start_draining))
+            .route("/drain", web::delete().to(stop_draining))
+            .route("/reload", web::post().to(reload)),
+    );
+}
+
+fn parse_peer_id(id: &str) -> Result<PeerId> {
+    PeerId::from_str(id).map_err(|_| error::ErrorBadRequest("invalid peer id"))
+}
+
+async fn kick_peer(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    id: web::Path<String>,
+) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    let peer = parse_peer_id(&id)?;
+    let connected = state
+        .handle
+        .kick(peer)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    if connected {
+        Ok(HttpResponse::NoContent())
+    } else {
+        Err(error::ErrorNotFound("peer is not connected"))
+    }
+}
+
+async fn get_bans(req: HttpRequest, state: web::Data<AdminState>) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    let bans = state
+        .handle
+        .bans()
+        .await
+        .map_err(error::ErrorInternalServerError)?
+        .iter()
+        .map(PeerId::to_string)
+        .collect::<Vec<String>>();
+
+    Ok(web::Json(bans))
+}
+
+async fn ban_peer(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    id: web::Path<String>,
+) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    let peer = parse_peer_id(&id)?;
+    state
+        .handle
+        .ban(peer)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+async fn unban_peer(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    id: web::Path<String>,
+) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    let peer = parse_peer_id(&id)?;
+    state
+        .handle
+        .unban(peer)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+/// Traffic of every peer, its reservation and circuits.
+async fn get_stats(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    traffic: web::Data<SharedTraffic>,
+) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    Ok(web::Json(traffic.report()))
+}
+
+/// Let the peer over its traffic quota reserve and open circuits again.
+async fn reset_quota(
+    req: HttpRequest,
+    state: web::Data<AdminState>,
+    traffic: web::Data<SharedTraffic>,
+    id: web::Path<String>,
+) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    let peer = parse_peer_id(&id)?;
+    if traffic.reset_quota(&peer) {
+        Ok(HttpResponse::NoContent())
+    } else {
+        Err(error::ErrorNotFound("peer is not known"))
+    }
+}
+
+async fn start_draining(req: HttpRequest, state: web::Data<AdminState>) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    state
+        .handle
+        .set_draining(true)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+async fn stop_draining(req: HttpRequest, state: web::Data<AdminState>) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    state
+        .handle
+        .set_draining(false)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+#[derive(Serialize)]
+struct ReloadResult {
+    /// Settings that were applied, the rest requires restart of the relay.
+    applied: Vec<&'static str>,
+}
+
+/// Re-read the config file and apply the settings that can change at runtime.
+async fn reload(req: HttpRequest, state: web::Data<AdminState>) -> Result<impl Responder> {
+    state.authorize(&req)?;
+    let settings = Settings::load(state.config_path.as_deref()).map_err(error::ErrorBadRequest)?;
+    let banned = settings
+        .banned_peers()
+        .map_err(error::ErrorBadRequest)?
+        .into_iter()
+        .collect::<HashSet<PeerId>>();
+
+    let http_rate = settings.limits.relay_config().http_rate_per_ip;
+    state
+        .rate_limiter
+        .lock()
+        .map_err(|_| error::ErrorInternalServerError("rate limiter error"))?
+        .set_rate(http_rate);
+
+    let (unbanned, banned) = state
+        .config_bans
+        .lock()
+        .map(|mut config_bans| replace_config_bans(&mut config_bans, banned))
+        .map_err(|_| error::ErrorInternalServerError("config bans error"))?;
+    for peer in unbanned {
+        state
+            .handle
+            .unban(peer)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+    for peer in banned {
+        state
+            .handle
+            .ban(peer)
+            .await
+            .map_err(error::ErrorInternalServerError)?;
+    }
+
+    Ok(web::Json(ReloadResult {
+        applied: vec!["limits.http_requests_per_ip_per_minute", "banned_peers"],
+    }))
+}
+
+/// Replace the bans of the config file, returns the peers to unban and to ban. Bans made with
+/// the admin API or restored from the store are not touched.
+fn replace_config_bans(
+    config_bans: &mut HashSet<PeerId>,
+    banned: HashSet<PeerId>,
+) -> (Vec<PeerId>, Vec<PeerId>) {
+    let unbanned = config_bans.difference(&banned).cloned().collect();
+    let added = banned.difference(config_bans).cloned().collect();
+    *config_bans = banned;
+    (unbanned, added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_replaces_only_config_bans() {
+        let (kept, removed, added) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut config_bans = HashSet::from([kept, removed]);
+
+        let (unbanned, banned) =
+            replace_config_bans(&mut config_bans, HashSet::from([kept, added]));
+        assert_eq!(unbanned, vec![removed]);
+        assert_eq!(banned, vec![added]);
+        assert_eq!(config_bans, HashSet::from([kept, added]));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_content_and_length() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret\0"));
+        assert!(!constant_time_eq(b"secret", b""));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
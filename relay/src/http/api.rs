@@ -1,16 +1,107 @@
-use libp2p::PeerId;
+use std::time::Instant;
+
 use serde::{Deserialize, Serialize};
 
-use actix_web::{error, web, Responder, Result};
+use actix_web::dev::Service;
+use actix_web::{error, web, HttpResponse, Responder, Result};
 
-use crate::{RelayInfo, SharedStore};
+use super::rate_limit::limit_rate;
+use crate::{PeerInfo, RelayInfo, SharedHealth, SharedMetrics, SharedStore, SharedTraffic};
 
 pub fn api_config(app: &mut web::ServiceConfig) {
-    app.service(
-        web::scope("/api")
-            .route("/peers", web::get().to(get_peer_list))
-            .route("/relay", web::get().to(get_relay_info)),
-    );
+    app.route("/metrics", web::get().to(get_metrics))
+        .route("/health", web::get().to(get_health))
+        .route("/ready", web::get().to(get_ready))
+        .service(
+            web::scope("/api")
+                // Wrapped first, so that the rejected requests are counted in the metrics.
+                .wrap_fn(limit_rate)
+                .wrap_fn(|req, srv| {
+                    let metrics = req.app_data::<web::Data<SharedMetrics>>().cloned();
+                    let method = req.method().to_string();
+                    let start = Instant::now();
+                    let res = srv.call(req);
+                    async move {
+                        let res = res.await?;
+                        if let Some(metrics) = metrics {
+                            // Matched pattern keeps the labels bounded, unlike the requested path.
+                            let path = res
+                                .request()
+                                .match_pattern()
+                                .unwrap_or_else(|| "unmatched".to_string());
+                            metrics.record_http(
+                                method,
+                                path,
+                                res.status().as_u16(),
+                                start.elapsed(),
+                            );
+                        }
+                        Ok(res)
+                    }
+                })
+                .route("/peers", web::get().to(get_peer_list))
+                .route("/relay", web::get().to(get_relay_info))
+                .route("/stats", web::get().to(get_stats)),
+        );
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    running: bool,
+    listeners: usize,
+    draining: bool,
+    store: bool,
+}
+
+impl HealthStatus {
+    fn new(health: &SharedHealth, store: &SharedStore) -> Self {
+        Self {
+            running: health.is_running(),
+            listeners: health.listeners(),
+            draining: health.is_draining(),
+            store: store.lock().is_ok(),
+        }
+    }
+
+    /// The relay accepts connections and reservations and the store is usable.
+    fn is_ready(&self) -> bool {
+        self.running && self.listeners > 0 && !self.draining && self.store
+    }
+
+    fn respond(self, ok: bool) -> HttpResponse {
+        if ok {
+            HttpResponse::Ok().json(self)
+        } else {
+            HttpResponse::ServiceUnavailable().json(self)
+        }
+    }
+}
+
+/// Liveness, the swarm event loop is running.
+async fn get_health(
+    health: web::Data<SharedHealth>,
+    store: web::Data<SharedStore>,
+) -> HttpResponse {
+    let status = HealthStatus::new(&health, &store);
+    let ok = status.running;
+    status.respond(ok)
+}
+
+/// Readiness, the relay accepts connections and reservations and the store is usable.
+async fn get_ready(health: web::Data<SharedHealth>, store: web::Data<SharedStore>) -> HttpResponse {
+    let status = HealthStatus::new(&health, &store);
+    let ok = status.is_ready();
+    status.respond(ok)
+}
+
+async fn get_metrics(metrics: web::Data<SharedMetrics>) -> Result<impl Responder> {
+    let body = metrics
+        .encode()
+        .map_err(|_| error::ErrorInternalServerError("metrics error"))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+        .body(body))
 }
 
 async fn get_peer_list(store: web::Data<SharedStore>) -> Result<impl Responder> {
@@ -35,15 +126,26 @@ async fn get_relay_info(store: web::Data<SharedStore>) -> Result<impl Responder>
     Ok(web::Json(res))
 }
 
+/// Traffic totals of the relay, traffic of the single peers and circuits is in the admin API.
+async fn get_stats(traffic: web::Data<SharedTraffic>) -> impl Responder {
+    web::Json(traffic.summary())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct WebPeerInfo {
     addr: String,
+    #[serde(default)]
+    addrs: Vec<String>,
+    #[serde(default)]
+    relay_addrs: Vec<String>,
 }
 
-impl From<&PeerId> for WebPeerInfo {
-    fn from(peer: &PeerId) -> Self {
+impl From<&PeerInfo> for WebPeerInfo {
+    fn from(peer: &PeerInfo) -> Self {
         Self {
-            addr: peer.to_string(),
+            addr: peer.peer_id.to_string(),
+            addrs: peer.addrs.clone(),
+            relay_addrs: peer.relay_addrs.clone(),
         }
     }
 }
@@ -52,13 +154,54 @@ impl From<&PeerId> for WebPeerInfo {
 pub struct WebRelayInfo {
     peer_id: String,
     ips: Vec<String>,
+    #[serde(default)]
+    federated: Vec<String>,
 }
 
 impl From<RelayInfo> for WebRelayInfo {
-    fn from(peer: RelayInfo) -> Self {
+    fn from(relay: RelayInfo) -> Self {
         Self {
-            peer_id: peer.peer_id.to_string(),
-            ips: peer.addrs,
+            ips: relay.advertised_addrs(),
+            peer_id: relay.peer_id,
+            federated: relay.federated_addrs,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ready_status() -> HealthStatus {
+        HealthStatus {
+            running: true,
+            listeners: 1,
+            draining: false,
+            store: true,
+        }
+    }
+
+    #[test]
+    fn ready_only_when_serving() {
+        assert!(ready_status().is_ready());
+        let not_ready = [
+            HealthStatus {
+                running: false,
+                ..ready_status()
+            },
+            HealthStatus {
+                listeners: 0,
+                ..ready_status()
+            },
+            HealthStatus {
+                draining: true,
+                ..ready_status()
+            },
+            HealthStatus {
+                store: false,
+                ..ready_status()
+            },
+        ];
+        assert!(not_ready.iter().all(|status| !status.is_ready()));
+    }
+}
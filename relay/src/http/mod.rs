@@ -1,3 +1,7 @@
+mod admin;
 mod api;
+mod rate_limit;
 
+pub use admin::*;
 pub use api::*;
+pub use rate_limit::*;
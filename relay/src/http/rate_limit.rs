@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{error, web, Error};
+
+use crate::RateLimit;
+
+pub type SharedRateLimiter = Arc<Mutex<HttpRateLimiter>>;
+
+/// Number of tracked addresses after which the expired windows are cleaned up.
+const CLEANUP_THRESHOLD: usize = 1024;
+
+/// Fixed window rate limiter of the HTTP API requests per IP address.
+pub struct HttpRateLimiter {
+    rate: RateLimit,
+    windows: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl HttpRateLimiter {
+    pub fn new(rate: RateLimit) -> Self {
+        Self {
+            rate,
+            windows: HashMap::new(),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: RateLimit) {
+        self.rate = rate;
+    }
+
+    /// Count the request, returns false if the address exceeded the limit.
+    pub fn try_next(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let interval = self.rate.interval;
+        if self.windows.len() > CLEANUP_THRESHOLD {
+            self.windows
+                .retain(|_, (start, _)| now.duration_since(*start) < interval);
+        }
+
+        let (start, count) = self.windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= interval {
+            *start = now;
+            *count = 0;
+        }
+
+        *count = count.saturating_add(1);
+        *count <= self.rate.limit
+    }
+}
+
+/// Middleware of the HTTP scopes, requests over the limit get `429 Too Many Requests`.
+pub(crate) fn limit_rate<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = Error>,
+{
+    let res = if is_rate_limited(&req) {
+        Err(req)
+    } else {
+        Ok(srv.call(req))
+    };
+    async move {
+        match res {
+            Ok(res) => res.await,
+            Err(req) => Ok(req.error_response(error::ErrorTooManyRequests("rate limit exceeded"))),
+        }
+    }
+}
+
+/// Requests are not limited if the rate limiter is not configured or the address is unknown.
+fn is_rate_limited(req: &ServiceRequest) -> bool {
+    let limiter = req.app_data::<web::Data<SharedRateLimiter>>();
+    match (limiter, req.peer_addr()) {
+        (Some(limiter), Some(addr)) => limiter
+            .lock()
+            .map(|mut limiter| !limiter.try_next(addr.ip(), Instant::now()))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn limits_requests_per_address_and_window() {
+        let start = Instant::now();
+        let mut limiter = HttpRateLimiter::new(RateLimit::per_minute(2));
+        assert!(limiter.try_next(ip(1), start));
+        assert!(limiter.try_next(ip(1), start));
+        assert!(!limiter.try_next(ip(1), start));
+        assert!(limiter.try_next(ip(2), start));
+
+        assert!(limiter.try_next(ip(1), start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn new_rate_applies_to_current_window() {
+        let start = Instant::now();
+        let mut limiter = HttpRateLimiter::new(RateLimit::per_minute(1));
+        assert!(limiter.try_next(ip(1), start));
+        assert!(!limiter.try_next(ip(1), start));
+
+        limiter.set_rate(RateLimit::per_minute(3));
+        assert!(limiter.try_next(ip(1), start));
+    }
+
+    #[test]
+    fn expired_windows_are_cleaned_up() {
+        let start = Instant::now();
+        let mut limiter = HttpRateLimiter::new(RateLimit::per_minute(1));
+        for n in 0..=CLEANUP_THRESHOLD as u32 {
+            limiter.try_next(ip(n), start);
+        }
+
+        limiter.try_next(ip(0), start + Duration::from_secs(60));
+        assert_eq!(limiter.windows.len(), 1);
+    }
+}
@@ -1,10 +1,26 @@
+mod audit;
+mod config;
+mod file_store;
+mod health;
 mod http;
+mod logging;
+mod metrics;
+mod settings;
 mod store;
 mod swarm;
+mod traffic;
 
+pub use audit::*;
+pub use config::*;
+pub use file_store::*;
+pub use health::*;
 pub use http::*;
+pub use logging::*;
+pub use metrics::*;
+pub use settings::*;
 pub use store::*;
 pub use swarm::*;
+pub use traffic::*;
 
 use std::sync::{Arc, Mutex};
 pub type SharedStore = Arc<Mutex<dyn PeerStore>>;
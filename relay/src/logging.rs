@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use common::{BlueError, BlueResult};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+use crate::{AuditRotation, LogFormat, Settings, AUDIT_TARGET};
+
+/// Install the global subscriber. Logs go to stdout in the configured format, audit events are
+/// additionally written as JSON to the audit file if it's set, regardless of the log level.
+/// The returned guard flushes the audit file when dropped, keep it until the relay exits.
+pub fn init_logging(settings: &Settings) -> BlueResult<Option<WorkerGuard>> {
+    // `RUST_LOG` takes precedence over the configured level.
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&settings.log_level))
+        .map_err(BlueError::local_err)?;
+    let stdout = match settings.log_format {
+        LogFormat::Text => fmt::layer().boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+
+    let (audit, guard) = match &settings.audit_file {
+        Some(path) => {
+            let (writer, guard) =
+                tracing_appender::non_blocking(audit_appender(path, settings.audit_rotation)?);
+            let layer = fmt::layer()
+                .json()
+                .with_writer(writer)
+                .with_filter(filter_fn(|metadata| metadata.target() == AUDIT_TARGET));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(stdout.with_filter(filter))
+        .with(audit)
+        .try_init()
+        .map_err(BlueError::local_err)?;
+
+    Ok(guard)
+}
+
+/// Rotated files get the date appended to the file name, e.g. `audit.log.2022-09-01`.
+fn audit_appender(path: &Path, rotation: AuditRotation) -> BlueResult<RollingFileAppender> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| BlueError::local_err("audit file has no name"))?;
+    let rotation = match rotation {
+        AuditRotation::Hourly => Rotation::HOURLY,
+        AuditRotation::Daily => Rotation::DAILY,
+        AuditRotation::Never => Rotation::NEVER,
+    };
+
+    Ok(RollingFileAppender::new(rotation, dir, file_name))
+}
@@ -0,0 +1,127 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libp2p::bandwidth::BandwidthSinks;
+use libp2p::metrics::Recorder;
+use libp2p::swarm::SwarmEvent;
+use prometheus_client::encoding::text::{encode, Encode};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+
+use crate::Event;
+
+pub type SharedMetrics = Arc<Metrics>;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Encode)]
+struct HttpLabels {
+    method: String,
+    path: String,
+    status: String,
+}
+
+/// Relay metrics in the Prometheus format: libp2p swarm and protocol events, bytes sent through
+/// the transport, including relayed circuits, and HTTP API requests.
+pub struct Metrics {
+    registry: Registry,
+    libp2p: libp2p::metrics::Metrics,
+    bandwidth: Mutex<Option<Arc<BandwidthSinks>>>,
+    inbound_bytes: Gauge,
+    outbound_bytes: Gauge,
+    http_requests: Family<HttpLabels, Counter>,
+    http_duration: Family<HttpLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::default();
+        let libp2p = libp2p::metrics::Metrics::new(&mut registry);
+
+        let sub_registry = registry.sub_registry_with_prefix("relay");
+        let inbound_bytes = Gauge::default();
+        sub_registry.register(
+            "inbound_bytes",
+            "Bytes received by the transport",
+            Box::new(inbound_bytes.clone()),
+        );
+        let outbound_bytes = Gauge::default();
+        sub_registry.register(
+            "outbound_bytes",
+            "Bytes sent by the transport",
+            Box::new(outbound_bytes.clone()),
+        );
+
+        let sub_registry = registry.sub_registry_with_prefix("http");
+        let http_requests = Family::default();
+        sub_registry.register(
+            "requests",
+            "Number of handled HTTP requests",
+            Box::new(http_requests.clone()),
+        );
+        let http_duration: Family<HttpLabels, Histogram, fn() -> Histogram> =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.001, 2., 12)));
+        sub_registry.register(
+            "request_duration_seconds",
+            "Duration of handled HTTP requests",
+            Box::new(http_duration.clone()),
+        );
+
+        Self {
+            registry,
+            libp2p,
+            bandwidth: Mutex::new(None),
+            inbound_bytes,
+            outbound_bytes,
+            http_requests,
+            http_duration,
+        }
+    }
+}
+
+impl Metrics {
+    /// Encode all metrics in the OpenMetrics text format.
+    pub fn encode(&self) -> std::io::Result<Vec<u8>> {
+        if let Ok(Some(sinks)) = self.bandwidth.lock().as_deref() {
+            self.inbound_bytes.set(sinks.total_inbound());
+            self.outbound_bytes.set(sinks.total_outbound());
+        }
+
+        let mut buf = Vec::new();
+        encode(&mut buf, &self.registry)?;
+        Ok(buf)
+    }
+
+    pub fn record_http(&self, method: String, path: String, status: u16, duration: Duration) {
+        let labels = HttpLabels {
+            method,
+            path,
+            status: status.to_string(),
+        };
+        self.http_requests.get_or_create(&labels).inc();
+        self.http_duration
+            .get_or_create(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn set_bandwidth_sinks(&self, sinks: Arc<BandwidthSinks>) {
+        if let Ok(mut bandwidth) = self.bandwidth.lock() {
+            *bandwidth = Some(sinks);
+        }
+    }
+
+    pub(crate) fn record_swarm<THandleErr>(&self, event: &SwarmEvent<Event, THandleErr>) {
+        self.libp2p.record(event);
+
+        if let SwarmEvent::Behaviour(event) = event {
+            match event {
+                Event::Relay(event) => self.libp2p.record(event),
+                Event::Identify(event) => self.libp2p.record(event),
+                Event::Ping(event) => self.libp2p.record(event),
+                Event::Autonat(_) => {}
+                Event::Gossipsub(event) => self.libp2p.record(event),
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::Args;
+use common::{BlueError, BlueResult};
+use config_rs::{Config, Environment, File};
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+
+use crate::{RateLimit, RelayConfig, TrafficQuota};
+
+/// Prefix of the environment variables overriding the config file, e.g. `BB_RELAY_HTTP_BIND`
+/// or `BB_RELAY_LIMITS__MAX_CIRCUITS`.
+const ENV_PREFIX: &str = "BB_RELAY";
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    Memory,
+    /// Bans are kept in `store_path` between restarts.
+    File,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// How often a new audit file is started.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// Settings of the `bb-relay` binary, read from the TOML file and the environment.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// File with the relay secret key, a new one is generated if it doesn't exist. Without it
+    /// the relay gets a new peer id on every start.
+    pub identity: Option<PathBuf>,
+
+    /// Addresses the swarm listens on, `/ws` addresses accept websocket connections.
+    pub listen_addrs: Vec<String>,
+
+    /// Public addresses of the relay advertised to the peers, e.g. when the relay is behind NAT
+    /// or a load balancer.
+    pub external_addrs: Vec<String>,
+
+    /// Advertise the private listen addresses while there is no public address, for local
+    /// setups. Off by default, so that peers are not given loopback or private addresses.
+    pub advertise_private_addrs: bool,
+
+    /// Peers asked to confirm the public address of the relay, `/p2p/` suffix is required.
+    pub autonat_servers: Vec<String>,
+
+    /// Other relays sharing their peers with this one, `/p2p/` suffix is required.
+    pub federation_peers: Vec<String>,
+
+    /// Address of the HTTP API.
+    pub http_bind: SocketAddr,
+
+    pub store: StoreBackend,
+
+    /// File of the `file` store.
+    pub store_path: PathBuf,
+
+    /// Default log filter, `RUST_LOG` takes precedence.
+    pub log_level: String,
+
+    pub log_format: LogFormat,
+
+    /// JSON log of connections, reservations, circuits, denials, moderation and HTTP requests.
+    pub audit_file: Option<PathBuf>,
+
+    pub audit_rotation: AuditRotation,
+
+    pub limits: Limits,
+
+    /// Bearer token required by the admin endpoints, they are disabled without it.
+    pub admin_token: Option<String>,
+
+    /// Peers refused by the relay, applied on start and on reload.
+    pub banned_peers: Vec<String>,
+
+    /// On shutdown, relayed circuits and HTTP requests are given this many seconds to finish.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            identity: None,
+            listen_addrs: vec![
+                "/ip4/0.0.0.0/tcp/8042".to_string(),
+                "/ip6/::/tcp/8042".to_string(),
+            ],
+            external_addrs: Vec::new(),
+            advertise_private_addrs: false,
+            autonat_servers: Vec::new(),
+            federation_peers: Vec::new(),
+            http_bind: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080)),
+            store: StoreBackend::Memory,
+            store_path: PathBuf::from("bb-relay-store.json"),
+            log_level: "info".to_string(),
+            log_format: LogFormat::Text,
+            audit_file: None,
+            audit_rotation: AuditRotation::Daily,
+            limits: Limits::default(),
+            admin_token: None,
+            banned_peers: Vec::new(),
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl Settings {
+    /// Read the settings from the file if given, then override them with the environment.
+    pub fn load(path: Option<&Path>) -> BlueResult<Self> {
+        let mut builder = Config::builder();
+        if let Some(path) = path {
+            builder = builder.add_source(File::from(path));
+        }
+
+        builder
+            .add_source(
+                Environment::with_prefix(ENV_PREFIX)
+                    .prefix_separator("_")
+                    .separator("__")
+                    .list_separator(",")
+                    .with_list_parse_key("listen_addrs")
+                    .with_list_parse_key("banned_peers")
+                    .with_list_parse_key("external_addrs")
+                    .with_list_parse_key("autonat_servers")
+                    .with_list_parse_key("federation_peers")
+                    .try_parsing(true),
+            )
+            .build()
+            .and_then(Config::try_deserialize)
+            .map_err(BlueError::local_err)
+    }
+
+    /// Listen on the port on all IPv4 and IPv6 interfaces instead of the configured addresses.
+    pub fn with_swarm_port(mut self, port: u16) -> Self {
+        self.listen_addrs = all_interfaces(port, false);
+        self
+    }
+
+    /// Additionally listen for websocket connections on the port.
+    pub fn with_ws_port(mut self, port: u16) -> Self {
+        self.listen_addrs.extend(all_interfaces(port, true));
+        self
+    }
+
+    pub fn with_http_port(mut self, port: u16) -> Self {
+        self.http_bind.set_port(port);
+        self
+    }
+
+    pub fn with_external_addrs(mut self, addrs: Vec<String>) -> Self {
+        self.external_addrs.extend(addrs);
+        self
+    }
+
+    pub fn external_addrs(&self) -> BlueResult<Vec<Multiaddr>> {
+        parse_addrs(&self.external_addrs)
+    }
+
+    /// Peer ids and addresses of the AutoNAT servers.
+    pub fn autonat_servers(&self) -> BlueResult<Vec<(PeerId, Multiaddr)>> {
+        parse_peer_addrs(&self.autonat_servers)
+    }
+
+    /// Peer ids and addresses of the federated relays.
+    pub fn federation_peers(&self) -> BlueResult<Vec<(PeerId, Multiaddr)>> {
+        parse_peer_addrs(&self.federation_peers)
+    }
+
+    pub fn banned_peers(&self) -> BlueResult<Vec<PeerId>> {
+        self.banned_peers
+            .iter()
+            .map(|peer| PeerId::from_str(peer).map_err(BlueError::local_err))
+            .collect()
+    }
+
+    pub fn listen_addrs(&self) -> BlueResult<Vec<Multiaddr>> {
+        parse_addrs(&self.listen_addrs)
+    }
+}
+
+fn parse_addrs(addrs: &[String]) -> BlueResult<Vec<Multiaddr>> {
+    addrs
+        .iter()
+        .map(|addr| Multiaddr::from_str(addr).map_err(BlueError::local_err))
+        .collect()
+}
+
+/// Parse addresses ending with `/p2p/<peer id>`.
+fn parse_peer_addrs(addrs: &[String]) -> BlueResult<Vec<(PeerId, Multiaddr)>> {
+    parse_addrs(addrs)?
+        .into_iter()
+        .map(|addr| match addr.iter().last() {
+            Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+                .map(|peer| (peer, addr))
+                .map_err(|_| BlueError::local_err("invalid peer id")),
+            _ => Err(BlueError::local_err(format!("{} has no peer id", addr))),
+        })
+        .collect()
+}
+
+fn all_interfaces(port: u16, ws: bool) -> Vec<String> {
+    [
+        Protocol::from(Ipv4Addr::UNSPECIFIED),
+        Protocol::from(Ipv6Addr::UNSPECIFIED),
+    ]
+    .into_iter()
+    .map(|ip| {
+        let addr = Multiaddr::empty().with(ip).with(Protocol::Tcp(port));
+        if ws {
+            addr.with(Protocol::Ws("/".into())).to_string()
+        } else {
+            addr.to_string()
+        }
+    })
+    .collect()
+}
+
+/// Relay resource limits, unset values keep the `RelayConfig` defaults.
+#[derive(Clone, Debug, Default, Deserialize, Args)]
+#[serde(default)]
+pub struct Limits {
+    /// Maximum number of reservations kept by the relay
+    #[clap(long)]
+    pub max_reservations: Option<usize>,
+
+    /// Maximum number of reservations kept for a single peer
+    #[clap(long)]
+    pub max_reservations_per_peer: Option<usize>,
+
+    /// Maximum number of circuits relayed at once
+    #[clap(long)]
+    pub max_circuits: Option<usize>,
+
+    /// Maximum number of circuits relayed for a single peer
+    #[clap(long)]
+    pub max_circuits_per_peer: Option<usize>,
+
+    /// Circuit is closed after this many seconds
+    #[clap(long)]
+    pub max_circuit_duration_secs: Option<u64>,
+
+    /// Circuit is closed after relaying this many bytes
+    #[clap(long)]
+    pub max_circuit_bytes: Option<u64>,
+
+    /// Reservations accepted from a single IP address per minute
+    #[clap(long)]
+    pub reservations_per_ip_per_minute: Option<u32>,
+
+    /// Circuits accepted from a single IP address per minute
+    #[clap(long)]
+    pub circuits_per_ip_per_minute: Option<u32>,
+
+    /// HTTP API requests accepted from a single IP address per minute
+    #[clap(long)]
+    pub http_requests_per_ip_per_minute: Option<u32>,
+
+    /// Bytes relayed for a single peer per hour, further reservations and circuits are denied
+    #[clap(long)]
+    pub max_bytes_per_peer_per_hour: Option<u64>,
+}
+
+impl Limits {
+    /// Values set in `other` take precedence.
+    pub fn merge(self, other: Limits) -> Self {
+        Self {
+            max_reservations: other.max_reservations.or(self.max_reservations),
+            max_reservations_per_peer: other
+                .max_reservations_per_peer
+                .or(self.max_reservations_per_peer),
+            max_circuits: other.max_circuits.or(self.max_circuits),
+            max_circuits_per_peer: other.max_circuits_per_peer.or(self.max_circuits_per_peer),
+            max_circuit_duration_secs: other
+                .max_circuit_duration_secs
+                .or(self.max_circuit_duration_secs),
+            max_circuit_bytes: other.max_circuit_bytes.or(self.max_circuit_bytes),
+            reservations_per_ip_per_minute: other
+                .reservations_per_ip_per_minute
+                .or(self.reservations_per_ip_per_minute),
+            circuits_per_ip_per_minute: other
+                .circuits_per_ip_per_minute
+                .or(self.circuits_per_ip_per_minute),
+            http_requests_per_ip_per_minute: other
+                .http_requests_per_ip_per_minute
+                .or(self.http_requests_per_ip_per_minute),
+            max_bytes_per_peer_per_hour: other
+                .max_bytes_per_peer_per_hour
+                .or(self.max_bytes_per_peer_per_hour),
+        }
+    }
+
+    pub fn relay_config(&self) -> RelayConfig {
+        let mut config = RelayConfig::default();
+        if let Some(max) = self.max_reservations {
+            config = config.with_max_reservations(max);
+        }
+        if let Some(max) = self.max_reservations_per_peer {
+            config = config.with_max_reservations_per_peer(max);
+        }
+        if let Some(max) = self.max_circuits {
+            config = config.with_max_circuits(max);
+        }
+        if let Some(max) = self.max_circuits_per_peer {
+            config = config.with_max_circuits_per_peer(max);
+        }
+        if let Some(secs) = self.max_circuit_duration_secs {
+            config = config.with_max_circuit_duration(Duration::from_secs(secs));
+        }
+        if let Some(bytes) = self.max_circuit_bytes {
+            config = config.with_max_circuit_bytes(bytes);
+        }
+        if let Some(limit) = self.reservations_per_ip_per_minute {
+            config = config.with_reservation_rate_per_ip(RateLimit::per_minute(limit));
+        }
+        if let Some(limit) = self.circuits_per_ip_per_minute {
+            config = config.with_circuit_rate_per_ip(RateLimit::per_minute(limit));
+        }
+        if let Some(limit) = self.http_requests_per_ip_per_minute {
+            config = config.with_http_rate_per_ip(RateLimit::per_minute(limit));
+        }
+        if let Some(bytes) = self.max_bytes_per_peer_per_hour {
+            config = config.with_traffic_quota(TrafficQuota::per_hour(bytes));
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn load_overrides_file_with_env() {
+        let path = env::temp_dir().join(format!("bb-relay-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            r#"
+log_level = "debug"
+http_bind = "0.0.0.0:8081"
+
+[limits]
+max_circuits = 4
+max_reservations = 8
+"#,
+        )
+        .unwrap();
+        env::set_var("BB_RELAY_HTTP_BIND", "127.0.0.1:9090");
+        env::set_var("BB_RELAY_LIMITS__MAX_CIRCUITS", "16");
+        env::set_var(
+            "BB_RELAY_LISTEN_ADDRS",
+            "/ip4/0.0.0.0/tcp/1,/ip4/0.0.0.0/tcp/2",
+        );
+
+        let settings = Settings::load(Some(&path));
+        env::remove_var("BB_RELAY_HTTP_BIND");
+        env::remove_var("BB_RELAY_LIMITS__MAX_CIRCUITS");
+        env::remove_var("BB_RELAY_LISTEN_ADDRS");
+        fs::remove_file(&path).unwrap();
+
+        let settings = settings.unwrap();
+        assert_eq!(settings.log_level, "debug");
+        assert_eq!(settings.http_bind, SocketAddr::from(([127, 0, 0, 1], 9090)));
+        assert_eq!(settings.limits.max_circuits, Some(16));
+        assert_eq!(settings.limits.max_reservations, Some(8));
+        assert_eq!(
+            settings.listen_addrs,
+            vec!["/ip4/0.0.0.0/tcp/1", "/ip4/0.0.0.0/tcp/2"]
+        );
+    }
+
+    #[test]
+    fn swarm_port_listens_on_all_interfaces() {
+        let settings = Settings::default().with_swarm_port(1).with_ws_port(2);
+        assert_eq!(
+            settings.listen_addrs,
+            vec![
+                "/ip4/0.0.0.0/tcp/1",
+                "/ip6/::/tcp/1",
+                "/ip4/0.0.0.0/tcp/2/ws",
+                "/ip6/::/tcp/2/ws",
+            ]
+        );
+    }
+
+    #[test]
+    fn peer_addrs_require_peer_id() {
+        let peer = PeerId::random();
+        let addr = format!("/ip4/1.2.3.4/tcp/1/p2p/{}", peer);
+        let parsed = parse_peer_addrs(&[addr.clone()]).unwrap();
+        assert_eq!(parsed, vec![(peer, Multiaddr::from_str(&addr).unwrap())]);
+
+        assert!(parse_peer_addrs(&["/ip4/1.2.3.4/tcp/1".to_string()]).is_err());
+    }
+
+    #[test]
+    fn merge_prefers_other_limits() {
+        let file = Limits {
+            max_circuits: Some(1),
+            max_reservations: Some(2),
+            ..Limits::default()
+        };
+        let cli = Limits {
+            max_circuits: Some(3),
+            max_bytes_per_peer_per_hour: Some(4),
+            ..Limits::default()
+        };
+
+        let limits = file.merge(cli);
+        assert_eq!(limits.max_circuits, Some(3));
+        assert_eq!(limits.max_reservations, Some(2));
+        assert_eq!(limits.max_bytes_per_peer_per_hour, Some(4));
+        assert_eq!(limits.max_circuits_per_peer, None);
+    }
+}
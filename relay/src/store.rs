@@ -1,20 +1,86 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use libp2p::PeerId;
+use common::{is_public_addr, BlueResult};
+use libp2p::{Multiaddr, PeerId};
 
 pub trait PeerStore: Send + Sync {
     fn add(&mut self, peer: PeerId);
-    fn get_all(&self) -> Vec<PeerId>;
+    /// Peers reserved on this relay and on the federated relays.
+    fn get_all(&self) -> Vec<PeerInfo>;
+    /// Peers reserved on this relay.
+    fn get_local(&self) -> Vec<PeerInfo>;
     fn remove(&mut self, peer: PeerId);
+    fn set_peer_addrs(&mut self, peer: PeerId, addrs: Vec<String>);
     fn set_relay_peer_id(&mut self, peer: &PeerId);
     fn append_relay_addr(&mut self, addr: String);
+    fn add_relay_external_addr(&mut self, addr: String);
+    /// Replace the address confirmed by AutoNAT, `None` when the relay isn't reachable.
+    fn set_relay_confirmed_addr(&mut self, addr: Option<String>);
+    fn set_relay_advertise_private_addrs(&mut self, advertise: bool);
     fn get_relay(&self) -> RelayInfo;
+    /// Replace the addresses of the federated relay and the peers reserved on it.
+    fn set_remote_peers(&mut self, relay: PeerId, relay_addrs: Vec<String>, peers: Vec<PeerInfo>);
+    /// Forget the federated relays that didn't update their peers for `max_age`.
+    fn expire_remote_peers(&mut self, max_age: Duration);
+    fn ban(&mut self, peer: PeerId);
+    fn unban(&mut self, peer: &PeerId);
+    /// Banned peers, persistent stores keep them between restarts of the relay.
+    fn banned(&self) -> Vec<PeerId>;
+    /// Persist the pending changes, called when the relay shuts down.
+    fn flush(&mut self) -> BlueResult<()>;
 }
 
 #[derive(Default, Clone)]
 pub struct RelayInfo {
     pub peer_id: String,
+    /// Addresses the relay is listening on.
     pub addrs: Vec<String>,
+    /// Addresses the relay is reachable at from the internet, configured by the operator.
+    pub external_addrs: Vec<String>,
+    /// Public address of the relay confirmed by AutoNAT, it changes with the NAT status.
+    pub confirmed_addr: Option<String>,
+    /// Addresses of the federated relays including their peer ids, clients fall back to them.
+    pub federated_addrs: Vec<String>,
+    /// Advertise the listen addresses when there is no public one, only useful in local setups.
+    pub advertise_private_addrs: bool,
+}
+
+impl RelayInfo {
+    /// Remote peers can dial only the public addresses, external ones go first, then the one
+    /// confirmed by AutoNAT. Without any, nothing is advertised until AutoNAT confirms an
+    /// address, unless private addresses are allowed, e.g. in a local setup.
+    pub fn advertised_addrs(&self) -> Vec<String> {
+        let mut addrs = self.external_addrs.clone();
+        for addr in self.confirmed_addr.iter() {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        for addr in self.addrs.iter() {
+            let is_public = Multiaddr::from_str(addr)
+                .map(|addr| is_public_addr(&addr))
+                .unwrap_or(false);
+            if is_public && !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        if addrs.is_empty() && self.advertise_private_addrs {
+            addrs = self.addrs.clone();
+        }
+        addrs
+    }
+}
+
+#[derive(Clone)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// Addresses the peer is listening on, as reported by identify protocol.
+    pub addrs: Vec<String>,
+    /// Addresses of the federated relay the peer has reservation on, including the relay peer
+    /// id, empty if the peer is reserved on this relay.
+    pub relay_addrs: Vec<String>,
 }
 
 #[derive(Default)]
@@ -22,8 +88,16 @@ pub struct MemoryPeerStore {
     /// Connection information about currently connected peers.
     peers: HashSet<PeerId>,
 
+    /// Listen addresses of the peers, the peer might not have a reservation yet.
+    peer_addrs: HashMap<PeerId, Vec<String>>,
+
     /// Connection information about relay itself.
     relay: RelayInfo,
+
+    /// Federated relays with the time of the last update, their addresses and reserved peers.
+    remote_peers: HashMap<PeerId, (Instant, Vec<String>, Vec<PeerInfo>)>,
+
+    banned: HashSet<PeerId>,
 }
 
 impl PeerStore for MemoryPeerStore {
@@ -31,13 +105,39 @@ impl PeerStore for MemoryPeerStore {
         self.peers.insert(peer);
     }
 
-    fn get_all(&self) -> Vec<PeerId> {
-        let peers = self.peers.iter().cloned().collect::<Vec<PeerId>>();
+    fn get_all(&self) -> Vec<PeerInfo> {
+        let mut peers = self.get_local();
+        for (_, _, remote_peers) in self.remote_peers.values() {
+            peers.extend(
+                remote_peers
+                    .iter()
+                    .filter(|peer| !self.peers.contains(&peer.peer_id))
+                    .cloned(),
+            );
+        }
+        peers
+    }
+
+    fn get_local(&self) -> Vec<PeerInfo> {
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer_id| PeerInfo {
+                peer_id: *peer_id,
+                addrs: self.peer_addrs.get(peer_id).cloned().unwrap_or_default(),
+                relay_addrs: Vec::new(),
+            })
+            .collect::<Vec<PeerInfo>>();
         peers
     }
 
     fn remove(&mut self, peer: PeerId) {
         self.peers.remove(&peer);
+        self.peer_addrs.remove(&peer);
+    }
+
+    fn set_peer_addrs(&mut self, peer: PeerId, addrs: Vec<String>) {
+        self.peer_addrs.insert(peer, addrs);
     }
 
     fn set_relay_peer_id(&mut self, id: &PeerId) {
@@ -51,7 +151,152 @@ impl PeerStore for MemoryPeerStore {
         self.relay.addrs.push(addr);
     }
 
+    fn add_relay_external_addr(&mut self, addr: String) {
+        if !self.relay.external_addrs.contains(&addr) {
+            self.relay.external_addrs.push(addr);
+        }
+    }
+
+    fn set_relay_confirmed_addr(&mut self, addr: Option<String>) {
+        self.relay.confirmed_addr = addr;
+    }
+
+    fn set_relay_advertise_private_addrs(&mut self, advertise: bool) {
+        self.relay.advertise_private_addrs = advertise;
+    }
+
     fn get_relay(&self) -> RelayInfo {
-        self.relay.clone()
+        RelayInfo {
+            federated_addrs: self
+                .remote_peers
+                .values()
+                .flat_map(|(_, relay_addrs, _)| relay_addrs.iter().cloned())
+                .collect(),
+            ..self.relay.clone()
+        }
+    }
+
+    fn set_remote_peers(&mut self, relay: PeerId, relay_addrs: Vec<String>, peers: Vec<PeerInfo>) {
+        self.remote_peers
+            .insert(relay, (Instant::now(), relay_addrs, peers));
+    }
+
+    fn expire_remote_peers(&mut self, max_age: Duration) {
+        self.remote_peers
+            .retain(|_, (updated, _, _)| updated.elapsed() < max_age);
+    }
+
+    fn ban(&mut self, peer: PeerId) {
+        self.banned.insert(peer);
+    }
+
+    fn unban(&mut self, peer: &PeerId) {
+        self.banned.remove(peer);
+    }
+
+    fn banned(&self) -> Vec<PeerId> {
+        self.banned.iter().cloned().collect()
+    }
+
+    /// Nothing to persist, bans are lost when the relay stops.
+    fn flush(&mut self) -> BlueResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay_info(
+        addrs: &[&str],
+        external_addrs: &[&str],
+        confirmed_addr: Option<&str>,
+    ) -> RelayInfo {
+        RelayInfo {
+            addrs: addrs.iter().map(|addr| addr.to_string()).collect(),
+            external_addrs: external_addrs.iter().map(|addr| addr.to_string()).collect(),
+            confirmed_addr: confirmed_addr.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn advertised_addrs_prefer_external() {
+        let relay = relay_info(
+            &["/ip4/10.0.0.1/tcp/8042", "/ip4/1.1.1.1/tcp/8042"],
+            &["/dns4/relay.example.com/tcp/8042"],
+            Some("/ip4/2.2.2.2/tcp/8042"),
+        );
+        assert_eq!(
+            relay.advertised_addrs(),
+            vec![
+                "/dns4/relay.example.com/tcp/8042",
+                "/ip4/2.2.2.2/tcp/8042",
+                "/ip4/1.1.1.1/tcp/8042",
+            ]
+        );
+    }
+
+    #[test]
+    fn private_addrs_are_advertised_only_when_allowed() {
+        let mut relay = relay_info(
+            &["/ip4/127.0.0.1/tcp/8042", "/ip4/10.0.0.1/tcp/8042"],
+            &[],
+            None,
+        );
+        assert!(relay.advertised_addrs().is_empty());
+
+        relay.advertise_private_addrs = true;
+        assert_eq!(
+            relay.advertised_addrs(),
+            vec!["/ip4/127.0.0.1/tcp/8042", "/ip4/10.0.0.1/tcp/8042"]
+        );
+
+        relay.confirmed_addr = Some("/ip4/1.1.1.1/tcp/8042".to_string());
+        assert_eq!(relay.advertised_addrs(), vec!["/ip4/1.1.1.1/tcp/8042"]);
+    }
+
+    #[test]
+    fn confirmed_addr_doesnt_replace_external() {
+        let mut store = MemoryPeerStore::default();
+        store.add_relay_external_addr("/ip4/1.1.1.1/tcp/8042".to_string());
+        store.set_relay_confirmed_addr(Some("/ip4/1.1.1.1/tcp/8042".to_string()));
+        store.set_relay_confirmed_addr(None);
+
+        assert_eq!(
+            store.get_relay().advertised_addrs(),
+            vec!["/ip4/1.1.1.1/tcp/8042"]
+        );
+    }
+
+    #[test]
+    fn remote_peers_are_listed_once() {
+        let mut store = MemoryPeerStore::default();
+        let (local, remote, relay) = (PeerId::random(), PeerId::random(), PeerId::random());
+        store.add(local);
+        let remote_peer = |peer_id| PeerInfo {
+            peer_id,
+            addrs: Vec::new(),
+            relay_addrs: vec!["/ip4/1.1.1.1/tcp/8042".to_string()],
+        };
+        store.set_remote_peers(
+            relay,
+            Vec::new(),
+            vec![remote_peer(local), remote_peer(remote)],
+        );
+
+        let mut peers = store
+            .get_all()
+            .iter()
+            .map(|peer| peer.peer_id)
+            .collect::<Vec<_>>();
+        peers.sort();
+        let mut expected = vec![local, remote];
+        expected.sort();
+        assert_eq!(peers, expected);
+
+        store.expire_remote_peers(Duration::ZERO);
+        assert_eq!(store.get_all().len(), 1);
     }
 }
@@ -0,0 +1,71 @@
+use common::{BlueError, BlueResult};
+use libp2p::PeerId;
+use tokio::sync::{mpsc, oneshot};
+
+/// Requests from the `AdminHandle` to the running swarm.
+pub(crate) enum AdminCommand {
+    /// Disconnect the peer, replies whether the peer was connected.
+    Kick(PeerId, oneshot::Sender<bool>),
+    Ban(PeerId),
+    Unban(PeerId),
+    Bans(oneshot::Sender<Vec<PeerId>>),
+    SetDraining(bool),
+    /// Shut the relay down gracefully, replies when it's done.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Moderation of the running relay, cheap to clone and usable from the HTTP handlers.
+#[derive(Clone)]
+pub struct AdminHandle {
+    commands: mpsc::Sender<AdminCommand>,
+}
+
+impl AdminHandle {
+    pub(crate) fn new(commands: mpsc::Sender<AdminCommand>) -> Self {
+        Self { commands }
+    }
+
+    /// Disconnect the peer and forget its reservation, the peer is free to come back.
+    pub async fn kick(&self, peer: PeerId) -> BlueResult<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.send(AdminCommand::Kick(peer, tx)).await?;
+        rx.await.map_err(BlueError::local_err)
+    }
+
+    /// Disconnect the peer and refuse its connections until it's unbanned.
+    pub async fn ban(&self, peer: PeerId) -> BlueResult<()> {
+        self.send(AdminCommand::Ban(peer)).await
+    }
+
+    pub async fn unban(&self, peer: PeerId) -> BlueResult<()> {
+        self.send(AdminCommand::Unban(peer)).await
+    }
+
+    pub async fn bans(&self) -> BlueResult<Vec<PeerId>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(AdminCommand::Bans(tx)).await?;
+        rx.await.map_err(BlueError::local_err)
+    }
+
+    /// While draining, new reservations and renewals are denied, so that peers move to other
+    /// relays before this one is shut down. Existing circuits are kept.
+    pub async fn set_draining(&self, draining: bool) -> BlueResult<()> {
+        self.send(AdminCommand::SetDraining(draining)).await
+    }
+
+    /// Stop accepting reservations, tell the peers to move to other relays and close the
+    /// connections once the circuits are finished or the shutdown timeout passes. Resolves when
+    /// the swarm is stopped.
+    pub async fn shutdown(&self) -> BlueResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.send(AdminCommand::Shutdown(tx)).await?;
+        rx.await.map_err(BlueError::local_err)
+    }
+
+    async fn send(&self, command: AdminCommand) -> BlueResult<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| BlueError::local_err("swarm is not running"))
+    }
+}
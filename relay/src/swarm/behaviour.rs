@@ -1,8 +1,19 @@
-use common::BlueResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use common::{BlueError, BlueResult};
+use libp2p::autonat;
+use libp2p::gossipsub::{
+    Gossipsub, GossipsubConfigBuilder, GossipsubEvent, MessageAuthenticity, ValidationMode,
+};
 use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
 use libp2p::ping::{Ping, PingConfig, PingEvent};
+use libp2p::relay::v2::relay::rate_limiter::RateLimiter;
 use libp2p::relay::v2::relay::{self, Relay};
-use libp2p::{identity, NetworkBehaviour, PeerId};
+use libp2p::{identity, Multiaddr, NetworkBehaviour, PeerId};
+
+use crate::{registry_topic, RelayConfig, SharedTraffic};
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
@@ -10,17 +21,77 @@ pub struct Behaviour {
     relay: Relay,
     ping: Ping,
     identify: Identify,
+    pub autonat: autonat::Behaviour,
+    /// Registry updates of the federated relays.
+    pub gossip: Gossipsub,
+    #[behaviour(ignore)]
+    draining: Arc<AtomicBool>,
 }
 
 impl Behaviour {
-    pub fn new(key: &identity::Keypair) -> BlueResult<Self> {
+    pub fn new(
+        key: &identity::Keypair,
+        config: &RelayConfig,
+        traffic: SharedTraffic,
+    ) -> BlueResult<Self> {
         let peer_id = PeerId::from(key.public());
+        let draining = Arc::new(AtomicBool::new(false));
+        let mut relay_config = config.to_libp2p()?;
+        relay_config
+            .reservation_rate_limiters
+            .push(Box::new(DrainLimiter(draining.clone())));
+        relay_config
+            .reservation_rate_limiters
+            .push(Box::new(QuotaLimiter(traffic.clone())));
+        relay_config
+            .circuit_src_rate_limiters
+            .push(Box::new(QuotaLimiter(traffic)));
+
+        let gossip_config = GossipsubConfigBuilder::default()
+            .validation_mode(ValidationMode::Strict)
+            .validate_messages() // Only updates from the federated relays are forwarded.
+            .build()
+            .map_err(BlueError::local_err)?;
+        let mut gossip = Gossipsub::new(MessageAuthenticity::Signed(key.clone()), gossip_config)
+            .map_err(BlueError::local_err)?;
+        gossip
+            .subscribe(&registry_topic())
+            .map_err(BlueError::local_err)?;
+
         Ok(Self {
-            relay: Relay::new(peer_id, Default::default()),
+            relay: Relay::new(peer_id, relay_config),
             ping: Ping::new(PingConfig::new().with_keep_alive(true)),
-            identify: Identify::new(IdentifyConfig::new("/TODO/0.0.1".to_string(), key.public())),
+            identify: Identify::new(IdentifyConfig::new(
+                "/beyond-blue-relay/0.1.0".to_string(),
+                key.public(),
+            )),
+            autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+            gossip,
+            draining,
         })
     }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+}
+
+/// Denies all reservations while the relay is draining.
+struct DrainLimiter(Arc<AtomicBool>);
+
+impl RateLimiter for DrainLimiter {
+    fn try_next(&mut self, _peer: PeerId, _addr: &Multiaddr, _now: Instant) -> bool {
+        !self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Denies reservations and circuits of the peers over their traffic quota.
+struct QuotaLimiter(SharedTraffic);
+
+impl RateLimiter for QuotaLimiter {
+    fn try_next(&mut self, peer: PeerId, _addr: &Multiaddr, now: Instant) -> bool {
+        !self.0.is_over_quota(&peer, now)
+    }
 }
 
 #[derive(Debug)]
@@ -28,6 +99,8 @@ pub enum Event {
     Ping(PingEvent),
     Identify(IdentifyEvent),
     Relay(relay::Event),
+    Autonat(autonat::Event),
+    Gossipsub(GossipsubEvent),
 }
 
 impl From<PingEvent> for Event {
@@ -47,3 +120,15 @@ impl From<relay::Event> for Event {
         Event::Relay(e)
     }
 }
+
+impl From<autonat::Event> for Event {
+    fn from(e: autonat::Event) -> Self {
+        Event::Autonat(e)
+    }
+}
+
+impl From<GossipsubEvent> for Event {
+    fn from(e: GossipsubEvent) -> Self {
+        Event::Gossipsub(e)
+    }
+}
@@ -0,0 +1,116 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use libp2p::gossipsub::IdentTopic;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+
+use crate::PeerInfo;
+
+/// Gossip topic the federated relays publish their peers on.
+pub(crate) const REGISTRY_TOPIC: &str = "/beyond-blue/relay-registry/1.0.0";
+
+/// How often the relay publishes its peers and redials the federated relays.
+pub(crate) const REGISTRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Peers of the federated relay are forgotten when it misses this many updates.
+pub(crate) const REGISTRY_MAX_MISSED: u32 = 3;
+
+pub(crate) fn registry_topic() -> IdentTopic {
+    IdentTopic::new(REGISTRY_TOPIC)
+}
+
+/// Snapshot of the peers reserved on the relay, the relay is the signed source of the message.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RegistryUpdate {
+    /// Addresses the relay is reachable at, without the relay peer id.
+    pub relay_addrs: Vec<String>,
+    pub peers: Vec<RegistryPeer>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RegistryPeer {
+    pub peer_id: String,
+    pub addrs: Vec<String>,
+}
+
+impl RegistryUpdate {
+    pub(crate) fn new(relay_addrs: Vec<String>, peers: Vec<PeerInfo>) -> Self {
+        Self {
+            relay_addrs,
+            peers: peers
+                .into_iter()
+                .map(|peer| RegistryPeer {
+                    peer_id: peer.peer_id.to_string(),
+                    addrs: peer.addrs,
+                })
+                .collect(),
+        }
+    }
+
+    /// Addresses of the `relay` including its peer id and the peers reserved on it.
+    pub(crate) fn into_peers(self, relay: PeerId) -> (Vec<String>, Vec<PeerInfo>) {
+        let relay_addrs = self
+            .relay_addrs
+            .iter()
+            .map(|addr| format!("{}/p2p/{}", addr, relay))
+            .collect::<Vec<String>>();
+
+        let peers = self
+            .peers
+            .into_iter()
+            .filter_map(|peer| {
+                Some(PeerInfo {
+                    peer_id: PeerId::from_str(&peer.peer_id).ok()?,
+                    addrs: peer.addrs,
+                    relay_addrs: relay_addrs.clone(),
+                })
+            })
+            .collect();
+        (relay_addrs, peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_lists_peers_reserved_on_relay() {
+        let (relay, peer) = (PeerId::random(), PeerId::random());
+        let update = RegistryUpdate::new(
+            vec!["/ip4/1.1.1.1/tcp/8042".to_string()],
+            vec![PeerInfo {
+                peer_id: peer,
+                addrs: vec!["/ip4/2.2.2.2/tcp/4000".to_string()],
+                relay_addrs: Vec::new(),
+            }],
+        );
+        let data = serde_json::to_vec(&update).unwrap();
+
+        let (relay_addrs, peers) = serde_json::from_slice::<RegistryUpdate>(&data)
+            .unwrap()
+            .into_peers(relay);
+        let relay_addr = format!("/ip4/1.1.1.1/tcp/8042/p2p/{}", relay);
+        assert_eq!(relay_addrs, vec![relay_addr.clone()]);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, peer);
+        assert_eq!(peers[0].addrs, vec!["/ip4/2.2.2.2/tcp/4000"]);
+        assert_eq!(peers[0].relay_addrs, vec![relay_addr]);
+    }
+
+    #[test]
+    fn invalid_peers_are_skipped() {
+        let update = RegistryUpdate {
+            relay_addrs: Vec::new(),
+            peers: vec![RegistryPeer {
+                peer_id: "not a peer id".to_string(),
+                addrs: Vec::new(),
+            }],
+        };
+
+        let (relay_addrs, peers) = update.into_peers(PeerId::random());
+        assert!(relay_addrs.is_empty());
+        assert!(peers.is_empty());
+    }
+}
@@ -1,5 +1,9 @@
+mod admin;
 mod behaviour;
+mod federation;
 mod swarm;
 
+pub use admin::*;
 pub use behaviour::*;
+pub(crate) use federation::*;
 pub use swarm::*;
@@ -1,39 +1,76 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use common::*;
 use futures::{select, FutureExt, StreamExt};
 
 use libp2p::{
+    autonat,
+    bandwidth::BandwidthLogging,
     core::{
         muxing::StreamMuxerBox,
         transport::{self, OrTransport},
         upgrade,
     },
     dns::DnsConfig,
+    gossipsub::{GossipsubEvent, GossipsubMessage, MessageAcceptance, MessageId},
+    identify::{IdentifyEvent, IdentifyInfo},
     identity, noise,
     relay::v2::{client::Client, relay},
-    swarm::{SwarmBuilder, SwarmEvent},
+    swarm::{AddressScore, SwarmBuilder, SwarmEvent},
     tcp::{GenTcpConfig, TcpTransport},
+    websocket::WsConfig,
     Multiaddr, PeerId, Transport,
 };
-use log::info;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
 
-use crate::{Event, PeerStore, SharedStore};
+use crate::{
+    audit, registry_topic, AdminCommand, AdminHandle, Event, Health, PeerStore, RegistryUpdate,
+    RelayConfig, SharedHealth, SharedMetrics, SharedStore, SharedTraffic, REGISTRY_INTERVAL,
+    REGISTRY_MAX_MISSED,
+};
 
 type RelaySwarm = libp2p::swarm::Swarm<crate::swarm::Behaviour>;
 
+const ADMIN_CHANNEL_SIZE: usize = 16;
+
+/// Default time given to the relayed circuits to finish when the relay shuts down.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time given to the swarm to deliver the shutdown announcement before connections are closed.
+const SHUTDOWN_ANNOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Time given to the connections to close once the shutdown timed out.
+const SHUTDOWN_CLOSE_DELAY: Duration = Duration::from_secs(1);
+
 pub struct Swarm {
     swarm: RelaySwarm,
     store: SharedStore,
-    stop_tx: Option<oneshot::Sender<()>>,
-    stop_rx: Option<oneshot::Receiver<()>>,
+    metrics: SharedMetrics,
+    traffic: SharedTraffic,
+    admin_tx: mpsc::Sender<AdminCommand>,
+    admin_rx: mpsc::Receiver<AdminCommand>,
+    /// Peers banned with the `AdminHandle`.
+    banned: HashSet<PeerId>,
+    health: SharedHealth,
+    /// Relays sharing their peers with this one.
+    federation: HashMap<PeerId, Multiaddr>,
+    /// Number of circuits currently relayed.
+    circuits: usize,
+    shutdown_timeout: Duration,
+    /// Set by the `AdminHandle` to stop the event loop, replied to once the relay is shut down.
+    shutdown_ack: Option<oneshot::Sender<()>>,
 }
 
 impl Swarm {
     pub async fn new_with_default_transport(
         local_key: identity::Keypair,
         store: Arc<Mutex<dyn PeerStore>>,
+        metrics: SharedMetrics,
+        traffic: SharedTraffic,
+        config: &RelayConfig,
     ) -> BlueResult<Self> {
         let local_peer_id = PeerId::from(local_key.public());
         info!("Local peer id: {:?}", local_peer_id);
@@ -44,24 +81,42 @@ impl Swarm {
             .into_authentic(&local_key)
             .expect("Signing libp2p-noise static DH keypair failed.");
 
-        let transport = OrTransport::new(
-            relay_transport,
-            DnsConfig::system(TcpTransport::new(GenTcpConfig::default().port_reuse(true)))
+        // Websocket transport allows browser clients to connect to relay.
+        let ws_transport = WsConfig::new(
+            DnsConfig::system(TcpTransport::new(GenTcpConfig::default()))
                 .await
                 .map_err(BlueError::local_err)?,
-        )
-        .upgrade(upgrade::Version::V1)
-        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-        .multiplex(libp2p_yamux::YamuxConfig::default())
-        .boxed();
+        );
+
+        // Relayed circuits are carried by the connections of both peers, so the bytes are counted
+        // on the way in and on the way out.
+        let counted_traffic = traffic.clone();
+        let (transport, bandwidth) = BandwidthLogging::new(OrTransport::new(
+            relay_transport,
+            OrTransport::new(
+                ws_transport,
+                DnsConfig::system(TcpTransport::new(GenTcpConfig::default().port_reuse(true)))
+                    .await
+                    .map_err(BlueError::local_err)?,
+            )
+            // Bytes of every connection are counted, so that they can be attributed to the peers.
+            .map(move |stream, endpoint| counted_traffic.count(stream, &endpoint)),
+        ));
+        metrics.set_bandwidth_sinks(bandwidth);
+
+        let transport = transport
+            .upgrade(upgrade::Version::V1)
+            .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+            .multiplex(libp2p_yamux::YamuxConfig::default())
+            .boxed();
 
         store
             .lock()
             .map_err(BlueError::local_err)?
             .set_relay_peer_id(&local_peer_id);
 
-        let behaviour = crate::Behaviour::new(&local_key)?;
-        Self::try_new(transport, behaviour, local_peer_id, store)
+        let behaviour = crate::Behaviour::new(&local_key, config, traffic.clone())?;
+        Self::try_new(transport, behaviour, local_peer_id, store, metrics, traffic)
     }
 
     pub fn try_new(
@@ -69,66 +124,384 @@ impl Swarm {
         behaviour: crate::Behaviour,
         peer_id: PeerId,
         store: Arc<Mutex<dyn PeerStore>>,
+        metrics: SharedMetrics,
+        traffic: SharedTraffic,
     ) -> BlueResult<Self> {
-        let (stop_tx, stop_rx) = oneshot::channel();
+        let (admin_tx, admin_rx) = mpsc::channel(ADMIN_CHANNEL_SIZE);
         let swarm = SwarmBuilder::new(transport, behaviour, peer_id)
             .dial_concurrency_factor(10_u8.try_into().map_err(BlueError::local_err)?)
             .build();
         Ok(Self {
             swarm,
             store,
-            stop_tx: Some(stop_tx),
-            stop_rx: Some(stop_rx),
+            metrics,
+            traffic,
+            admin_tx,
+            admin_rx,
+            banned: HashSet::new(),
+            health: Arc::new(Health::default()),
+            federation: HashMap::new(),
+            circuits: 0,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown_ack: None,
         })
     }
 
-    pub async fn listen_on(&mut self, addr: Multiaddr) -> BlueResult<()> {
-        self.swarm.listen_on(addr).map_err(BlueError::local_err)?;
+    pub fn health(&self) -> SharedHealth {
+        self.health.clone()
+    }
+
+    pub fn admin_handle(&self) -> AdminHandle {
+        AdminHandle::new(self.admin_tx.clone())
+    }
+
+    /// Disconnect the peer and refuse its connections.
+    pub fn ban(&mut self, peer: PeerId) -> BlueResult<()> {
+        audit::record_admin("banned", &peer);
+        let mut store = self.store.lock().map_err(BlueError::local_err)?;
+        store.remove(peer);
+        store.ban(peer);
+        self.swarm.ban_peer_id(peer);
+        self.banned.insert(peer);
         Ok(())
     }
 
-    pub async fn spawn(&mut self) -> BlueResult<()> {
-        let rx = self
-            .stop_rx
-            .take()
-            .ok_or_else(|| BlueError::local_err("already stopped"))?;
-
-        select! {
-            _ = rx.fuse() => {},
-            _ = self.event_loop().fuse() => {},
-        };
+    pub fn unban(&mut self, peer: PeerId) -> BlueResult<()> {
+        audit::record_admin("unbanned", &peer);
+        self.store
+            .lock()
+            .map_err(BlueError::local_err)?
+            .unban(&peer);
+        self.swarm.unban_peer_id(peer);
+        self.banned.remove(&peer);
+        Ok(())
+    }
 
+    /// Advertise the address to the peers, e.g. the public address of the relay behind NAT.
+    pub fn add_external_address(&mut self, addr: Multiaddr) -> BlueResult<()> {
+        self.swarm
+            .add_external_address(addr.clone(), AddressScore::Infinite);
+        self.store
+            .lock()
+            .map_err(BlueError::local_err)?
+            .add_relay_external_addr(addr.to_string());
         Ok(())
     }
 
-    pub fn stop(&mut self) {
-        self.stop_tx.take();
+    /// Advertise the private listen addresses while there is no public one, e.g. when the relay
+    /// and the peers run on the same machine or network.
+    pub fn advertise_private_addrs(&mut self, advertise: bool) -> BlueResult<()> {
+        self.store
+            .lock()
+            .map_err(BlueError::local_err)?
+            .set_relay_advertise_private_addrs(advertise);
+        Ok(())
+    }
+
+    /// Ask the peer, e.g. another relay, to confirm that the relay is reachable from the internet.
+    pub fn add_autonat_server(&mut self, peer: PeerId, addr: Option<Multiaddr>) {
+        self.swarm.behaviour_mut().autonat.add_server(peer, addr);
+    }
+
+    /// Share the registry of peers with the other relay, the relay is also used as AutoNAT server.
+    pub fn add_federation_peer(&mut self, peer: PeerId, addr: Multiaddr) -> BlueResult<()> {
+        info!("federating with {} at {}", peer, addr);
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.gossip.add_explicit_peer(&peer);
+        behaviour.autonat.add_server(peer, Some(addr.clone()));
+        self.federation.insert(peer, addr.clone());
+        // Unreachable relay is redialed periodically, it might not be started yet.
+        if let Err(e) = self.swarm.dial(addr) {
+            info!("failed to dial federated relay {}: {:?}", peer, e);
+        }
+        Ok(())
+    }
+
+    /// Maximum time the shutdown waits for the relayed circuits to finish.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    pub async fn listen_on(&mut self, addr: Multiaddr) -> BlueResult<()> {
+        self.swarm.listen_on(addr).map_err(BlueError::local_err)?;
+        Ok(())
+    }
+
+    /// Run the swarm until it fails or `AdminHandle::shutdown` is called, the store is flushed
+    /// in both cases.
+    pub async fn spawn(&mut self) -> BlueResult<()> {
+        self.health.set_running(true);
+        let result = self.event_loop().await;
+        if result.is_ok() {
+            self.shutdown().await;
+        }
+        let flushed = self
+            .store
+            .lock()
+            .map_err(BlueError::local_err)
+            .and_then(|mut store| store.flush());
+        self.health.set_running(false);
+
+        if let Some(ack) = self.shutdown_ack.take() {
+            _ = ack.send(());
+        }
+        result.and(flushed)
     }
 
     async fn event_loop(&mut self) -> BlueResult<()> {
-        loop {
-            match self.swarm.select_next_some().await {
-                SwarmEvent::Behaviour(Event::Relay(relay::Event::ReservationReqAccepted {
-                    src_peer_id: peer_id,
-                    renewed: _,
-                })) => {
-                    self.store
-                        .lock()
-                        .map_err(BlueError::local_err)?
-                        .add(peer_id);
+        let mut registry_interval = tokio::time::interval(REGISTRY_INTERVAL);
+        while self.shutdown_ack.is_none() {
+            select! {
+                _ = registry_interval.tick().fuse() => {
+                    self.traffic.prune(Instant::now());
+                    self.update_federation()?;
+                }
+                command = self.admin_rx.recv().fuse() => {
+                    if let Some(command) = command {
+                        self.handle_admin_command(command)?;
+                    }
                 }
-                SwarmEvent::Behaviour(Event::Relay(event)) => {
-                    println!("{:?}", event)
+                event = self.swarm.select_next_some() => {
+                    self.metrics.record_swarm(&event);
+                    self.traffic.record_swarm(&event);
+                    audit::record_swarm(&event);
+                    self.count_circuits(&event);
+                    if matches!(
+                        event,
+                        SwarmEvent::NewListenAddr { .. }
+                            | SwarmEvent::ExpiredListenAddr { .. }
+                            | SwarmEvent::ListenerClosed { .. }
+                    ) {
+                        self.health.set_listeners(self.swarm.listeners().count());
+                    }
+                    match event {
+                        SwarmEvent::Behaviour(Event::Relay(relay::Event::ReservationReqAccepted {
+                            src_peer_id: peer_id,
+                            renewed: _,
+                        })) => {
+                            self.store
+                                .lock()
+                                .map_err(BlueError::local_err)?
+                                .add(peer_id);
+                        }
+                        SwarmEvent::Behaviour(Event::Autonat(autonat::Event::StatusChanged {
+                            old,
+                            new,
+                        })) => {
+                            info!("NAT status changed from {:?} to {:?}", old, new);
+                            // Configured external addresses are kept regardless of the status.
+                            let confirmed_addr = match new {
+                                autonat::NatStatus::Public(addr) => Some(addr.to_string()),
+                                _ => None,
+                            };
+                            self.store
+                                .lock()
+                                .map_err(BlueError::local_err)?
+                                .set_relay_confirmed_addr(confirmed_addr);
+                        }
+                        SwarmEvent::Behaviour(Event::Gossipsub(GossipsubEvent::Message {
+                            propagation_source,
+                            message_id,
+                            message,
+                        })) => {
+                            self.handle_registry_update(propagation_source, message_id, message)?;
+                        }
+                        SwarmEvent::Behaviour(Event::Identify(IdentifyEvent::Received {
+                            peer_id,
+                            info: IdentifyInfo { listen_addrs, .. },
+                        })) => {
+                            self.store
+                                .lock()
+                                .map_err(BlueError::local_err)?
+                                .set_peer_addrs(
+                                    peer_id,
+                                    listen_addrs.iter().map(|a| a.to_string()).collect(),
+                                );
+                        }
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            self.store
+                                .lock()
+                                .map_err(BlueError::local_err)?
+                                .append_relay_addr(address.to_string());
+                            info!("Listening on {:?}", address);
+                        }
+                        _ => {}
+                    }
                 }
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    self.store
-                        .lock()
-                        .map_err(BlueError::local_err)?
-                        .append_relay_addr(address.to_string());
-                    println!("Listening on {:?}", address);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drain the relay, announce the shutdown to the peers and wait for the circuits to finish,
+    /// the remaining connections are closed after the shutdown timeout.
+    async fn shutdown(&mut self) {
+        info!("shutting down relay with {} circuits", self.circuits);
+        self.swarm.behaviour().set_draining(true);
+        self.health.set_draining(true);
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossip
+            .publish(relay_shutdown_topic(), Vec::new())
+        {
+            info!("failed to announce shutdown: {:?}", e);
+        }
+
+        let announce = tokio::time::sleep(SHUTDOWN_ANNOUNCE_DELAY).fuse();
+        let deadline = tokio::time::sleep(self.shutdown_timeout).fuse();
+        futures::pin_mut!(announce, deadline);
+        let mut announced = false;
+        while !announced || self.circuits > 0 {
+            select! {
+                event = self.swarm.select_next_some() => self.record_shutdown_event(&event),
+                _ = announce => announced = true,
+                _ = deadline => {
+                    info!("shutdown timed out, closing {} circuits", self.circuits);
+                    break;
                 }
-                _ => {}
             }
         }
+
+        let peers = self
+            .swarm
+            .connected_peers()
+            .cloned()
+            .collect::<Vec<PeerId>>();
+        for peer in peers {
+            _ = self.swarm.disconnect_peer_id(peer);
+        }
+
+        // Connections are closed by the swarm, it has to be polled until they are. The rest of
+        // the shutdown timeout is used, or a short delay if it's over already.
+        let close = tokio::time::sleep(SHUTDOWN_CLOSE_DELAY).fuse();
+        futures::pin_mut!(close);
+        while self.swarm.connected_peers().next().is_some() {
+            select! {
+                event = self.swarm.select_next_some() => self.record_shutdown_event(&event),
+                _ = deadline => break,
+                _ = close => break,
+            }
+        }
+        info!(
+            "relay shut down, {} connections left",
+            self.swarm.connected_peers().count()
+        );
+    }
+
+    fn record_shutdown_event<THandleErr: std::fmt::Debug>(
+        &mut self,
+        event: &SwarmEvent<Event, THandleErr>,
+    ) {
+        self.metrics.record_swarm(event);
+        self.traffic.record_swarm(event);
+        audit::record_swarm(event);
+        self.count_circuits(event);
+    }
+
+    /// Keep track of the relayed circuits, the shutdown waits for them to finish.
+    fn count_circuits<THandleErr>(&mut self, event: &SwarmEvent<Event, THandleErr>) {
+        match event {
+            SwarmEvent::Behaviour(Event::Relay(relay::Event::CircuitReqAccepted { .. })) => {
+                self.circuits += 1;
+            }
+            SwarmEvent::Behaviour(Event::Relay(relay::Event::CircuitClosed { .. })) => {
+                self.circuits = self.circuits.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    /// Publish the local peers to the federated relays, forget the silent ones and redial the
+    /// disconnected ones.
+    fn update_federation(&mut self) -> BlueResult<()> {
+        if self.federation.is_empty() {
+            return Ok(());
+        }
+
+        let (relay, peers) = {
+            let mut store = self.store.lock().map_err(BlueError::local_err)?;
+            store.expire_remote_peers(REGISTRY_INTERVAL * REGISTRY_MAX_MISSED);
+            (store.get_relay(), store.get_local())
+        };
+        let update = RegistryUpdate::new(relay.advertised_addrs(), peers);
+        let data = serde_json::to_vec(&update).map_err(BlueError::local_err)?;
+        // Fails when no federated relay is connected yet, the update is sent on the next tick.
+        _ = self
+            .swarm
+            .behaviour_mut()
+            .gossip
+            .publish(registry_topic(), data);
+
+        let disconnected = self
+            .federation
+            .iter()
+            .filter(|(peer, _)| !self.swarm.is_connected(peer))
+            .map(|(_, addr)| addr.clone())
+            .collect::<Vec<Multiaddr>>();
+        for addr in disconnected {
+            if let Err(e) = self.swarm.dial(addr.clone()) {
+                info!("failed to dial federated relay {}: {:?}", addr, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Accept the registry updates only from the federated relays.
+    fn handle_registry_update(
+        &mut self,
+        propagation_source: PeerId,
+        message_id: MessageId,
+        message: GossipsubMessage,
+    ) -> BlueResult<()> {
+        let update = message
+            .source
+            .filter(|relay| self.federation.contains_key(relay))
+            .zip(serde_json::from_slice::<RegistryUpdate>(&message.data).ok());
+
+        let acceptance = match update {
+            Some((relay, update)) => {
+                let (relay_addrs, peers) = update.into_peers(relay);
+                self.store
+                    .lock()
+                    .map_err(BlueError::local_err)?
+                    .set_remote_peers(relay, relay_addrs, peers);
+                MessageAcceptance::Accept
+            }
+            None => MessageAcceptance::Reject,
+        };
+
+        _ = self
+            .swarm
+            .behaviour_mut()
+            .gossip
+            .report_message_validation_result(&message_id, &propagation_source, acceptance);
+        Ok(())
+    }
+
+    fn handle_admin_command(&mut self, command: AdminCommand) -> BlueResult<()> {
+        match command {
+            AdminCommand::Kick(peer, tx) => {
+                audit::record_admin("kicked", &peer);
+                self.store
+                    .lock()
+                    .map_err(BlueError::local_err)?
+                    .remove(peer);
+                _ = tx.send(self.swarm.disconnect_peer_id(peer).is_ok());
+            }
+            AdminCommand::Ban(peer) => self.ban(peer)?,
+            AdminCommand::Unban(peer) => self.unban(peer)?,
+            AdminCommand::Bans(tx) => {
+                _ = tx.send(self.banned.iter().cloned().collect());
+            }
+            AdminCommand::SetDraining(draining) => {
+                info!("draining: {}", draining);
+                self.swarm.behaviour().set_draining(draining);
+                self.health.set_draining(draining);
+            }
+            AdminCommand::Shutdown(ack) => self.shutdown_ack = Some(ack),
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,702 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use libp2p::core::ConnectedPoint;
+use libp2p::relay::v2::relay;
+use libp2p::swarm::SwarmEvent;
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+
+use crate::Event;
+
+pub type SharedTraffic = Arc<Traffic>;
+
+/// Number of closed circuits kept for the statistics.
+const CLOSED_CIRCUITS: usize = 256;
+
+/// At most `bytes` may be relayed for a single peer within every `interval`.
+#[derive(Clone, Copy, Debug)]
+pub struct TrafficQuota {
+    pub bytes: u64,
+    pub interval: Duration,
+}
+
+impl TrafficQuota {
+    pub fn per_hour(bytes: u64) -> Self {
+        Self {
+            bytes,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Bytes carried by a single connection, counted by the transport.
+#[derive(Default, Debug)]
+struct ConnectionTraffic {
+    inbound: AtomicU64,
+    outbound: AtomicU64,
+}
+
+impl ConnectionTraffic {
+    fn get(&self) -> (u64, u64) {
+        (
+            self.inbound.load(Ordering::Relaxed),
+            self.outbound.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Transport stream that counts the bytes of the connection.
+#[pin_project::pin_project]
+pub struct CountingStream<S> {
+    #[pin]
+    inner: S,
+    traffic: Arc<ConnectionTraffic>,
+}
+
+impl<S: AsyncRead> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let read = ready!(this.inner.poll_read(cx, buf))?;
+        this.traffic
+            .inbound
+            .fetch_add(read as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(read))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let written = ready!(this.inner.poll_write(cx, buf))?;
+        this.traffic
+            .outbound
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+struct Reservation {
+    since: Instant,
+    renewals: u32,
+    /// Bytes of the peer when the reservation was accepted.
+    start_bytes: u64,
+}
+
+struct OpenCircuit {
+    since: Instant,
+    /// Bytes of the source and destination peer when the circuit was accepted.
+    src_start_bytes: u64,
+    dst_start_bytes: u64,
+}
+
+struct PeerTraffic {
+    /// Connections of the peer, libp2p 0.46 doesn't tell which connection is closed, so closed
+    /// connections are recognized by the transport stream being dropped.
+    connections: Vec<Arc<ConnectionTraffic>>,
+    /// Bytes of the connections that are closed already.
+    closed_inbound: u64,
+    closed_outbound: u64,
+    reservation: Option<Reservation>,
+    /// Start of the quota interval and the bytes of the peer at that time.
+    quota_start: (Instant, u64),
+}
+
+impl PeerTraffic {
+    fn new(now: Instant) -> Self {
+        Self {
+            connections: Vec::new(),
+            closed_inbound: 0,
+            closed_outbound: 0,
+            reservation: None,
+            quota_start: (now, 0),
+        }
+    }
+
+    fn bytes(&self) -> (u64, u64) {
+        self.connections.iter().map(|traffic| traffic.get()).fold(
+            (self.closed_inbound, self.closed_outbound),
+            |(inbound, outbound), (i, o)| (inbound + i, outbound + o),
+        )
+    }
+
+    fn total(&self) -> u64 {
+        let (inbound, outbound) = self.bytes();
+        inbound + outbound
+    }
+
+    /// Move the bytes of the closed connections to the totals, only this store holds them.
+    fn collect_closed(&mut self) {
+        let (closed_inbound, closed_outbound) =
+            (&mut self.closed_inbound, &mut self.closed_outbound);
+        self.connections.retain(|traffic| {
+            if Arc::strong_count(traffic) > 1 {
+                return true;
+            }
+            let (inbound, outbound) = traffic.get();
+            *closed_inbound += inbound;
+            *closed_outbound += outbound;
+            false
+        });
+    }
+
+    /// Bytes relayed within the current quota interval, a new interval is started if the
+    /// current one is over.
+    fn quota_used(&mut self, interval: Duration, now: Instant) -> u64 {
+        let total = self.total();
+        let (start, start_bytes) = &mut self.quota_start;
+        if now.duration_since(*start) >= interval {
+            *start = now;
+            *start_bytes = total;
+        }
+        total.saturating_sub(*start_bytes)
+    }
+}
+
+#[derive(Default)]
+struct TrafficState {
+    peers: HashMap<PeerId, PeerTraffic>,
+    /// Open circuits by the source and destination peer, oldest first.
+    circuits: HashMap<(PeerId, PeerId), VecDeque<OpenCircuit>>,
+    closed_circuits: VecDeque<CircuitStats>,
+    closed_circuit_count: u64,
+}
+
+/// Traffic of the peers, their reservations and relayed circuits, with optional per peer quota.
+///
+/// Connections are counted by the transport. libp2p doesn't report the bytes of a circuit, so
+/// they are estimated from the bytes of its source and destination peer while the circuit was
+/// open, which include their other circuits and protocols.
+pub struct Traffic {
+    quota: Option<TrafficQuota>,
+    /// Connections counted by the transport that are not established yet, they are assigned to
+    /// the peer by the swarm.
+    pending: Mutex<HashMap<Multiaddr, Vec<Weak<ConnectionTraffic>>>>,
+    state: Mutex<TrafficState>,
+}
+
+impl Traffic {
+    pub fn new(quota: Option<TrafficQuota>) -> Self {
+        Self {
+            quota,
+            pending: Mutex::new(HashMap::new()),
+            state: Mutex::new(TrafficState::default()),
+        }
+    }
+
+    /// Count the bytes of the transport connection.
+    pub(crate) fn count<S>(&self, stream: S, endpoint: &ConnectedPoint) -> CountingStream<S> {
+        let traffic = Arc::new(ConnectionTraffic::default());
+        if let Ok(mut pending) = self.pending.lock() {
+            // Connections that failed to upgrade are dropped by now.
+            pending.retain(|_, connections| {
+                connections.retain(|traffic| traffic.strong_count() > 0);
+                !connections.is_empty()
+            });
+            pending
+                .entry(endpoint.get_remote_address().clone())
+                .or_default()
+                .push(Arc::downgrade(&traffic));
+        }
+
+        CountingStream {
+            inner: stream,
+            traffic,
+        }
+    }
+
+    pub(crate) fn record_swarm<THandleErr>(&self, event: &SwarmEvent<Event, THandleErr>) {
+        let now = Instant::now();
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                let traffic = self.pending.lock().ok().and_then(|mut pending| {
+                    let connections = pending.get_mut(endpoint.get_remote_address())?;
+                    // Connections to the same address are established in the order they were
+                    // counted.
+                    let traffic = (!connections.is_empty()).then(|| connections.remove(0));
+                    if connections.is_empty() {
+                        pending.remove(endpoint.get_remote_address());
+                    }
+                    traffic?.upgrade()
+                });
+                // Circuits to the relay itself are not counted by the transport.
+                if let Some(traffic) = traffic {
+                    state.add_connection(*peer_id, traffic, now);
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => state.close_connection(peer_id, *num_established),
+            SwarmEvent::Behaviour(Event::Relay(relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
+            })) => state.accept_reservation(*src_peer_id, *renewed, now),
+            SwarmEvent::Behaviour(Event::Relay(relay::Event::ReservationTimedOut {
+                src_peer_id,
+            })) => {
+                if let Some(peer) = state.peers.get_mut(src_peer_id) {
+                    peer.reservation = None;
+                }
+            }
+            SwarmEvent::Behaviour(Event::Relay(relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            })) => state.open_circuit(*src_peer_id, *dst_peer_id, now),
+            SwarmEvent::Behaviour(Event::Relay(relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                ..
+            })) => state.close_circuit(*src_peer_id, *dst_peer_id, now),
+            _ => {}
+        }
+    }
+
+    /// Peer exceeded its quota, new reservations and circuits from it are denied until the
+    /// quota interval is over. The traffic of the peer as the destination of circuits counts
+    /// too, it loses the reservation on the next renewal, so that no new circuits reach it.
+    pub fn is_over_quota(&self, peer: &PeerId, now: Instant) -> bool {
+        let quota = match self.quota {
+            Some(quota) => quota,
+            None => return false,
+        };
+        self.state
+            .lock()
+            .ok()
+            .and_then(|mut state| {
+                state
+                    .peers
+                    .get_mut(peer)
+                    .map(|peer| peer.quota_used(quota.interval, now) > quota.bytes)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Start a new quota interval for the peer, returns false if the peer is unknown.
+    pub fn reset_quota(&self, peer: &PeerId) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false,
+        };
+        match state.peers.get_mut(peer) {
+            Some(peer) => {
+                peer.quota_start = (Instant::now(), peer.total());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget disconnected peers, unless their quota interval is still running.
+    pub(crate) fn prune(&self, now: Instant) {
+        let interval = self.quota.map(|quota| quota.interval).unwrap_or_default();
+        if let Ok(mut state) = self.state.lock() {
+            state.prune(interval, now);
+        }
+    }
+
+    /// Totals of the relay.
+    pub fn summary(&self) -> TrafficSummary {
+        self.state
+            .lock()
+            .map(|state| state.summary())
+            .unwrap_or_default()
+    }
+
+    /// Totals together with the traffic of every peer and circuit.
+    pub fn report(&self) -> TrafficReport {
+        let now = Instant::now();
+        let quota = self.quota;
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return TrafficReport::default(),
+        };
+
+        let summary = state.summary();
+        let mut circuits = state
+            .circuits
+            .iter()
+            .flat_map(|(key, circuits)| {
+                circuits
+                    .iter()
+                    .map(|circuit| state.circuit_stats(*key, circuit, now, true))
+            })
+            .collect::<Vec<CircuitStats>>();
+        circuits.extend(state.closed_circuits.iter().cloned());
+
+        let peers = state
+            .peers
+            .iter_mut()
+            .map(|(peer_id, peer)| {
+                let (inbound_bytes, outbound_bytes) = peer.bytes();
+                let total = inbound_bytes + outbound_bytes;
+                let quota_used = quota.map(|quota| (peer.quota_used(quota.interval, now), quota));
+                PeerTrafficStats {
+                    peer_id: peer_id.to_string(),
+                    inbound_bytes,
+                    outbound_bytes,
+                    connections: peer.connections.len(),
+                    reservation: peer
+                        .reservation
+                        .as_ref()
+                        .map(|reservation| ReservationStats {
+                            duration_secs: now.duration_since(reservation.since).as_secs(),
+                            renewals: reservation.renewals,
+                            bytes: total.saturating_sub(reservation.start_bytes),
+                        }),
+                    quota_used_bytes: quota_used.map(|(used, _)| used),
+                    over_quota: quota_used
+                        .map(|(used, quota)| used > quota.bytes)
+                        .unwrap_or(false),
+                }
+            })
+            .collect();
+
+        TrafficReport {
+            summary,
+            peers,
+            circuits,
+        }
+    }
+}
+
+impl TrafficState {
+    fn add_connection(&mut self, peer: PeerId, traffic: Arc<ConnectionTraffic>, now: Instant) {
+        self.peers
+            .entry(peer)
+            .or_insert_with(|| PeerTraffic::new(now))
+            .connections
+            .push(traffic);
+    }
+
+    fn close_connection(&mut self, peer: &PeerId, num_established: u32) {
+        if let Some(peer) = self.peers.get_mut(peer) {
+            peer.collect_closed();
+            // Reservations don't outlive the connections.
+            if num_established == 0 {
+                peer.reservation = None;
+            }
+        }
+    }
+
+    fn accept_reservation(&mut self, peer: PeerId, renewed: bool, now: Instant) {
+        let peer = self
+            .peers
+            .entry(peer)
+            .or_insert_with(|| PeerTraffic::new(now));
+        let total = peer.total();
+        match &mut peer.reservation {
+            Some(reservation) if renewed => reservation.renewals += 1,
+            reservation => {
+                *reservation = Some(Reservation {
+                    since: now,
+                    renewals: 0,
+                    start_bytes: total,
+                })
+            }
+        }
+    }
+
+    fn open_circuit(&mut self, src: PeerId, dst: PeerId, now: Instant) {
+        let total = |peer: PeerId| {
+            self.peers
+                .get(&peer)
+                .map(PeerTraffic::total)
+                .unwrap_or_default()
+        };
+        let circuit = OpenCircuit {
+            since: now,
+            src_start_bytes: total(src),
+            dst_start_bytes: total(dst),
+        };
+        self.circuits
+            .entry((src, dst))
+            .or_default()
+            .push_back(circuit);
+    }
+
+    /// libp2p doesn't tell which of the circuits between the peers is closed, the oldest one is
+    /// assumed.
+    fn close_circuit(&mut self, src: PeerId, dst: PeerId, now: Instant) {
+        let key = (src, dst);
+        let circuit = self
+            .circuits
+            .get_mut(&key)
+            .and_then(|circuits| circuits.pop_front());
+        if self.circuits.get(&key).map_or(false, VecDeque::is_empty) {
+            self.circuits.remove(&key);
+        }
+
+        if let Some(circuit) = circuit {
+            let stats = self.circuit_stats(key, &circuit, now, false);
+            self.closed_circuit_count += 1;
+            if self.closed_circuits.len() >= CLOSED_CIRCUITS {
+                self.closed_circuits.pop_front();
+            }
+            self.closed_circuits.push_back(stats);
+        }
+    }
+
+    fn prune(&mut self, interval: Duration, now: Instant) {
+        self.peers.retain(|_, peer| {
+            peer.collect_closed();
+            !peer.connections.is_empty() || now.duration_since(peer.quota_start.0) < interval
+        });
+    }
+
+    fn summary(&self) -> TrafficSummary {
+        let (inbound_bytes, outbound_bytes) = self
+            .peers
+            .values()
+            .map(PeerTraffic::bytes)
+            .fold((0, 0), |(inbound, outbound), (i, o)| {
+                (inbound + i, outbound + o)
+            });
+        TrafficSummary {
+            inbound_bytes,
+            outbound_bytes,
+            reservations: self
+                .peers
+                .values()
+                .filter(|peer| peer.reservation.is_some())
+                .count(),
+            open_circuits: self.circuits.values().map(VecDeque::len).sum(),
+            closed_circuits: self.closed_circuit_count,
+        }
+    }
+
+    /// Bytes of the circuit are at most the bytes of either peer while it was open, the smaller
+    /// one is the estimate.
+    fn circuit_stats(
+        &self,
+        (src, dst): (PeerId, PeerId),
+        circuit: &OpenCircuit,
+        now: Instant,
+        open: bool,
+    ) -> CircuitStats {
+        let bytes_since = |peer: PeerId, start_bytes: u64| {
+            self.peers
+                .get(&peer)
+                .map(|peer| peer.total().saturating_sub(start_bytes))
+                .unwrap_or_default()
+        };
+        CircuitStats {
+            src_peer_id: src.to_string(),
+            dst_peer_id: dst.to_string(),
+            duration_ms: now.duration_since(circuit.since).as_millis() as u64,
+            estimated_bytes: bytes_since(src, circuit.src_start_bytes)
+                .min(bytes_since(dst, circuit.dst_start_bytes)),
+            open,
+        }
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct TrafficSummary {
+    /// Bytes received from the connected and recently disconnected peers.
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+    pub reservations: usize,
+    pub open_circuits: usize,
+    /// Number of all circuits closed since the start of the relay.
+    pub closed_circuits: u64,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct TrafficReport {
+    pub summary: TrafficSummary,
+    pub peers: Vec<PeerTrafficStats>,
+    /// Open circuits and the recently closed ones.
+    pub circuits: Vec<CircuitStats>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PeerTrafficStats {
+    pub peer_id: String,
+    pub inbound_bytes: u64,
+    pub outbound_bytes: u64,
+    pub connections: usize,
+    pub reservation: Option<ReservationStats>,
+    /// Bytes within the current quota interval, if the quota is set.
+    pub quota_used_bytes: Option<u64>,
+    pub over_quota: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReservationStats {
+    pub duration_secs: u64,
+    pub renewals: u32,
+    /// Bytes of the peer since the reservation was accepted.
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CircuitStats {
+    pub src_peer_id: String,
+    pub dst_peer_id: String,
+    pub duration_ms: u64,
+    /// Upper bound of the relayed bytes, the smaller of the bytes of both peers while the
+    /// circuit was open.
+    pub estimated_bytes: u64,
+    pub open: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Connection with the given bytes, the second handle stands for the transport stream.
+    fn connection(inbound: u64, outbound: u64) -> (Arc<ConnectionTraffic>, Arc<ConnectionTraffic>) {
+        let traffic = Arc::new(ConnectionTraffic::default());
+        traffic.inbound.store(inbound, Ordering::Relaxed);
+        traffic.outbound.store(outbound, Ordering::Relaxed);
+        (traffic.clone(), traffic)
+    }
+
+    fn add_bytes(traffic: &ConnectionTraffic, inbound: u64) {
+        traffic.inbound.fetch_add(inbound, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn quota_window_restarts_after_interval() {
+        let interval = Duration::from_secs(60);
+        let start = Instant::now();
+        let traffic = Traffic::new(Some(TrafficQuota {
+            bytes: 100,
+            interval,
+        }));
+        let peer = PeerId::random();
+        let (stream, counted) = connection(60, 60);
+        traffic
+            .state
+            .lock()
+            .unwrap()
+            .add_connection(peer, counted, start);
+
+        assert!(traffic.is_over_quota(&peer, start));
+        // The bytes of the previous interval don't count anymore.
+        let next = start + interval;
+        assert!(!traffic.is_over_quota(&peer, next));
+        add_bytes(&stream, 101);
+        assert!(traffic.is_over_quota(&peer, next));
+
+        assert!(traffic.reset_quota(&peer));
+        assert!(!traffic.is_over_quota(&peer, next));
+        assert!(!traffic.is_over_quota(&PeerId::random(), next));
+    }
+
+    #[test]
+    fn closed_connections_keep_their_bytes() {
+        let now = Instant::now();
+        let mut state = TrafficState::default();
+        let peer = PeerId::random();
+        let (first, counted) = connection(10, 20);
+        state.add_connection(peer, counted, now);
+        // Second connection from the same address.
+        let (second, counted) = connection(1, 2);
+        state.add_connection(peer, counted, now);
+        state.accept_reservation(peer, false, now);
+
+        drop(first);
+        state.close_connection(&peer, 1);
+        let traffic = &state.peers[&peer];
+        assert_eq!(traffic.connections.len(), 1);
+        assert_eq!(traffic.bytes(), (11, 22));
+        assert!(traffic.reservation.is_some());
+
+        drop(second);
+        state.close_connection(&peer, 0);
+        let traffic = &state.peers[&peer];
+        assert!(traffic.connections.is_empty());
+        assert_eq!(traffic.bytes(), (11, 22));
+        assert!(traffic.reservation.is_none());
+    }
+
+    #[test]
+    fn circuits_are_closed_oldest_first() {
+        let start = Instant::now();
+        let mut state = TrafficState::default();
+        let (src, dst) = (PeerId::random(), PeerId::random());
+        let (src_stream, counted) = connection(0, 0);
+        state.add_connection(src, counted, start);
+        let (dst_stream, counted) = connection(0, 0);
+        state.add_connection(dst, counted, start);
+
+        state.open_circuit(src, dst, start);
+        add_bytes(&src_stream, 1000);
+        add_bytes(&dst_stream, 400);
+        state.open_circuit(src, dst, start + Duration::from_secs(1));
+        add_bytes(&src_stream, 10);
+        add_bytes(&dst_stream, 10);
+
+        let end = start + Duration::from_secs(2);
+        state.close_circuit(src, dst, end);
+        let summary = state.summary();
+        assert_eq!(summary.open_circuits, 1);
+        assert_eq!(summary.closed_circuits, 1);
+        let closed = &state.closed_circuits[0];
+        assert_eq!(closed.duration_ms, 2000);
+        // Bytes of the destination are the smaller ones.
+        assert_eq!(closed.estimated_bytes, 410);
+
+        state.close_circuit(src, dst, end);
+        assert_eq!(state.closed_circuits[1].duration_ms, 1000);
+        assert_eq!(state.closed_circuits[1].estimated_bytes, 10);
+        assert!(state.circuits.is_empty());
+
+        // Unknown circuits are ignored.
+        state.close_circuit(dst, src, end);
+        assert_eq!(state.summary().closed_circuits, 2);
+    }
+
+    #[test]
+    fn prune_keeps_connected_and_quota_limited_peers() {
+        let interval = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut state = TrafficState::default();
+        let (connected, disconnected) = (PeerId::random(), PeerId::random());
+        let (_stream, counted) = connection(1, 1);
+        state.add_connection(connected, counted, start);
+        let (stream, counted) = connection(1, 1);
+        state.add_connection(disconnected, counted, start);
+        drop(stream);
+
+        state.prune(interval, start);
+        assert_eq!(state.peers.len(), 2);
+
+        state.prune(interval, start + interval);
+        assert!(state.peers.contains_key(&connected));
+        assert!(!state.peers.contains_key(&disconnected));
+    }
+}